@@ -2,41 +2,42 @@ use crate::event::Event;
 use std::fmt::Debug;
 use std::rc::Rc as Shared;
 
-/// Action is the trait for callbacks.
-pub trait Action<S, I>: Debug {
+/// Action is the trait for callbacks. C is the type of the context of the FSM,
+/// see FSM::set_context.
+pub trait Action<S, I, C = ()>: Debug {
     type Err: std::error::Error;
-    fn call(&self, e: &Event<S, I>) -> Result<(), Self::Err>;
+    fn call(&self, e: &Event<S, I, C>) -> Result<(), Self::Err>;
 }
 
-type WrapFn<'a, S, I, E> = Shared<dyn Fn(&Event<S, I>) -> Result<(), E> + 'a>;
+type WrapFn<'a, S, I, E, C> = Shared<dyn Fn(&Event<S, I, C>) -> Result<(), E> + 'a>;
 
 /// Closure is a wrapper around a closure that implements the Action trait.
 /// unsupport thread-safe
-pub struct Closure<'a, S, I, E>(pub(crate) WrapFn<'a, S, I, E>);
+pub struct Closure<'a, S, I, E, C = ()>(pub(crate) WrapFn<'a, S, I, E, C>);
 
-impl<'a, S, I, E> Closure<'a, S, I, E> {
+impl<'a, S, I, E, C> Closure<'a, S, I, E, C> {
     pub fn new<F>(f: F) -> Self
     where
-        F: Fn(&Event<S, I>) -> Result<(), E> + 'a,
+        F: Fn(&Event<S, I, C>) -> Result<(), E> + 'a,
     {
         Self(Shared::new(f))
     }
 }
 
-impl<'a, S, I, E: std::error::Error> Action<S, I> for Closure<'a, S, I, E> {
+impl<'a, S, I, E: std::error::Error, C> Action<S, I, C> for Closure<'a, S, I, E, C> {
     type Err = E;
-    fn call(&self, e: &Event<S, I>) -> Result<(), Self::Err> {
+    fn call(&self, e: &Event<S, I, C>) -> Result<(), Self::Err> {
         (self.0)(e)
     }
 }
 
-impl<'a, S, I, E> Debug for Closure<'a, S, I, E> {
+impl<'a, S, I, E, C> Debug for Closure<'a, S, I, E, C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "<Closure>")
     }
 }
 
-impl<'a, S, I, E> Clone for Closure<'a, S, I, E> {
+impl<'a, S, I, E, C> Clone for Closure<'a, S, I, E, C> {
     fn clone(&self) -> Self {
         Self(self.0.clone())
     }
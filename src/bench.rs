@@ -0,0 +1,215 @@
+//! Synthetic workloads to measure FSM throughput on your own hardware.
+//!
+//! Only available with the `bench` feature.
+//!
+//! ```rust
+//! use small_fsm::bench::Workload;
+//!
+//! let report = Workload {
+//!     states: 8,
+//!     events: 4,
+//!     ..Default::default()
+//! }
+//! .run(1_000);
+//! assert_eq!(1_000, report.transitions);
+//! println!("{}", report);
+//! ```
+
+use crate::{
+    action::Closure,
+    fsm::{FSMState, HookType, FSM},
+};
+use std::{
+    convert::Infallible,
+    fmt::{self, Display},
+    time::{Duration, Instant},
+};
+
+/// State is the state type of synthetic machines.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct State(String);
+
+impl FSMState for State {}
+
+impl AsRef<Self> for State {
+    fn as_ref(&self) -> &Self {
+        self
+    }
+}
+
+impl AsRef<str> for State {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Machine is the FSM type built by a Workload.
+pub type Machine = FSM<'static, State, Vec<u32>, Closure<'static, State, Vec<u32>, Infallible>>;
+
+/// Workload describes a synthetic machine and how it is driven.
+///
+/// Every event is valid from every state and moves the machine to another
+/// state, so each fired event is a real transition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Workload {
+    /// `states` is the number of states, at least 2.
+    pub states: usize,
+
+    /// `events` is the number of events, at least 1.
+    pub events: usize,
+
+    /// `targeted_hooks` is the number of states that get an Enter hook, and of
+    /// events that get a Before hook.
+    pub targeted_hooks: usize,
+
+    /// `global_hooks` registers one hook of each callback type for every
+    /// transition.
+    pub global_hooks: bool,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            states: 16,
+            events: 8,
+            targeted_hooks: 0,
+            global_hooks: false,
+        }
+    }
+}
+
+impl Workload {
+    /// build creates the machine described by the workload.
+    pub fn build(&self) -> Machine {
+        let states = self.states.max(2);
+        let events = self.events.max(1);
+        let state = |i: usize| State(format!("s{}", i));
+
+        let mut transitions = Vec::with_capacity(states * events);
+        for e in 0..events {
+            let step = 1 + e % (states - 1);
+            for s in 0..states {
+                transitions.push((format!("e{}", e), state(s), state((s + step) % states)));
+            }
+        }
+
+        let noop = || Closure::new(|_e| -> Result<(), Infallible> { Ok(()) });
+        let mut hooks = Vec::new();
+        for i in 0..self.targeted_hooks {
+            if i < states {
+                hooks.push((HookType::Enter(state(i)), noop()));
+            }
+            if i < events {
+                hooks.push((HookType::Before(format!("e{}", i)), noop()));
+            }
+        }
+        if self.global_hooks {
+            hooks.push((HookType::BeforeEvent, noop()));
+            hooks.push((HookType::LeaveState, noop()));
+            hooks.push((HookType::EnterState, noop()));
+            hooks.push((HookType::AfterEvent, noop()));
+        }
+
+        FSM::new(state(0), transitions, hooks)
+    }
+
+    /// run builds the machine and fires iterations events, cycling through
+    /// all events, and reports the timings.
+    pub fn run(&self, iterations: u64) -> Report {
+        let mut fsm = self.build();
+        let events: Vec<String> = (0..self.events.max(1)).map(|e| format!("e{}", e)).collect();
+        let args = Vec::new();
+
+        let mut report = Report::default();
+        let start = Instant::now();
+        for i in 0..iterations {
+            let event = &events[i as usize % events.len()];
+            let at = Instant::now();
+            let ret = fsm.on_event(event, Some(&args));
+            let latency = at.elapsed();
+
+            report.max = report.max.max(latency);
+            match ret {
+                Ok(_) => report.transitions += 1,
+                Err(_) => report.errors += 1,
+            }
+        }
+        report.elapsed = start.elapsed();
+        report
+    }
+}
+
+/// Report holds the results of a Workload run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// `transitions` is the number of successful transitions.
+    pub transitions: u64,
+
+    /// `errors` is the number of events that failed.
+    pub errors: u64,
+
+    /// `elapsed` is the total time of the run.
+    pub elapsed: Duration,
+
+    /// `max` is the slowest single on_event call.
+    pub max: Duration,
+}
+
+impl Report {
+    /// throughput returns the number of events handled per second.
+    pub fn throughput(&self) -> f64 {
+        let events = (self.transitions + self.errors) as f64;
+        events / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    /// mean returns the average latency of an on_event call.
+    pub fn mean(&self) -> Duration {
+        let events = self.transitions + self.errors;
+        if events == 0 {
+            return Duration::ZERO;
+        }
+        self.elapsed / events.min(u32::MAX as u64) as u32
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} transitions, {} errors in {:?} ({:.0} events/s, mean {:?}, max {:?})",
+            self.transitions,
+            self.errors,
+            self.elapsed,
+            self.throughput(),
+            self.mean(),
+            self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Workload;
+
+    #[test]
+    fn test_workload() {
+        let workload = Workload {
+            states: 3,
+            events: 5,
+            targeted_hooks: 2,
+            global_hooks: true,
+        };
+        let fsm = workload.build();
+        assert!(fsm.describe().contains("e3: s2 -> s1"));
+
+        let report = workload.run(100);
+        assert_eq!(100, report.transitions);
+        assert_eq!(0, report.errors);
+    }
+}
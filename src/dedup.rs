@@ -0,0 +1,189 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+/// Dedup suppresses identical events arriving within a time window.
+#[derive(Debug)]
+pub(crate) struct Dedup<I> {
+    // window is how long an event is remembered after it was seen.
+    window: Duration,
+
+    // seen maps the hash of (event, args) to the events with that hash, in
+    // the order they were seen. The events are kept so that a hash collision
+    // does not suppress a distinct event.
+    seen: HashMap<u64, VecDeque<Seen<I>>>,
+
+    // expiry holds the hash of every event in seen and when it was seen,
+    // oldest first, so expired events are evicted without scanning seen.
+    expiry: VecDeque<(Instant, u64)>,
+
+    // suppressed counts the events rejected as duplicates.
+    suppressed: u64,
+
+    // hash, eq and clone work on args, so the FSM does not need
+    // I: Hash + Eq + Clone itself.
+    hash: fn(&str, Option<&I>) -> u64,
+    eq: fn(&I, &I) -> bool,
+    clone: fn(&I) -> I,
+}
+
+// Seen is an event remembered by Dedup.
+#[derive(Debug)]
+struct Seen<I> {
+    event: String,
+    args: Option<I>,
+}
+
+impl<I> Dedup<I> {
+    pub(crate) fn new(window: Duration) -> Self
+    where
+        I: Hash + Eq + Clone,
+    {
+        Self {
+            window,
+            seen: HashMap::new(),
+            expiry: VecDeque::new(),
+            suppressed: 0,
+            hash: hash_event::<I>,
+            eq: I::eq,
+            clone: I::clone,
+        }
+    }
+
+    /// check records the event seen at now and returns true if an identical
+    /// one was seen within the window.
+    //
+    // Events are evicted once their window has elapsed, oldest first, so
+    // each check does work in proportion to the events it evicts.
+    pub(crate) fn check(&mut self, event: &str, args: Option<&I>, now: Instant) -> bool {
+        while let Some(&(at, hash)) = self.expiry.front() {
+            if now.saturating_duration_since(at) < self.window {
+                break;
+            }
+            self.expiry.pop_front();
+            if let Some(bucket) = self.seen.get_mut(&hash) {
+                // the oldest event of a bucket is the oldest in expiry
+                bucket.pop_front();
+                if bucket.is_empty() {
+                    self.seen.remove(&hash);
+                }
+            }
+        }
+
+        let eq = self.eq;
+        let hash = (self.hash)(event, args);
+        let bucket = self.seen.entry(hash).or_default();
+        let duplicate = bucket.iter().any(|seen| {
+            seen.event == event
+                && match (&seen.args, args) {
+                    (Some(a), Some(b)) => eq(a, b),
+                    (None, None) => true,
+                    _ => false,
+                }
+        });
+        if duplicate {
+            self.suppressed += 1;
+            return true;
+        }
+        bucket.push_back(Seen {
+            event: event.to_string(),
+            args: args.map(self.clone),
+        });
+        self.expiry.push_back((now, hash));
+        false
+    }
+
+    pub(crate) fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+}
+
+impl<I> Clone for Dedup<I> {
+    fn clone(&self) -> Self {
+        let clone = self.clone;
+        let seen = self
+            .seen
+            .iter()
+            .map(|(hash, bucket)| {
+                let bucket = bucket
+                    .iter()
+                    .map(|seen| Seen {
+                        event: seen.event.clone(),
+                        args: seen.args.as_ref().map(clone),
+                    })
+                    .collect();
+                (*hash, bucket)
+            })
+            .collect();
+        Self {
+            window: self.window,
+            seen,
+            expiry: self.expiry.clone(),
+            suppressed: self.suppressed,
+            hash: self.hash,
+            eq: self.eq,
+            clone,
+        }
+    }
+}
+
+// hash_event hashes event and args together.
+fn hash_event<I: Hash>(event: &str, args: Option<&I>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    event.hash(&mut hasher);
+    args.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dedup;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_dedup_check() {
+        let now = Instant::now();
+        let mut dedup = Dedup::new(Duration::from_secs(60));
+        assert!(!dedup.check("open", Some(&vec![1]), now));
+        assert!(dedup.check("open", Some(&vec![1]), now));
+        assert_eq!(1, dedup.suppressed());
+
+        // other args or another event are not duplicates
+        assert!(!dedup.check("open", Some(&vec![2]), now));
+        assert!(!dedup.check("close", Some(&vec![1]), now));
+        assert!(!dedup.check("open", None, now));
+        assert_eq!(1, dedup.suppressed());
+
+        // the window is measured on the time passed in, and expired events
+        // are forgotten
+        assert!(dedup.check("open", None, now + Duration::from_secs(59)));
+        assert!(!dedup.check("open", None, now + Duration::from_secs(120)));
+        assert_eq!(1, dedup.seen.len());
+        assert_eq!(1, dedup.expiry.len());
+
+        // a zero window never suppresses
+        let mut dedup = Dedup::<Vec<u32>>::new(Duration::ZERO);
+        assert!(!dedup.check("open", None, now));
+        assert!(!dedup.check("open", None, now));
+        assert_eq!(0, dedup.suppressed());
+    }
+
+    #[test]
+    fn test_dedup_collision() {
+        // every event hashes alike, yet distinct events are not suppressed
+        let now = Instant::now();
+        let mut dedup = Dedup::<u32>::new(Duration::from_secs(60));
+        dedup.hash = |_, _| 0;
+        assert!(!dedup.check("open", Some(&1), now));
+        assert!(!dedup.check("open", Some(&2), now));
+        assert!(!dedup.check("close", Some(&1), now));
+        assert!(dedup.check("open", Some(&2), now));
+
+        // expired events leave the bucket oldest first
+        let later = now + Duration::from_secs(60);
+        assert!(!dedup.check("open", Some(&1), later));
+        assert_eq!(1, dedup.seen[&0].len());
+    }
+}
@@ -18,4 +18,172 @@ pub enum FSMError<S: Display> {
 
     #[error("event {0} inappropriate in current state {1}")]
     InvalidEvent(S, S),
+
+    #[error("event sequence {0} is not newer than last applied sequence {1}")]
+    StaleEvent(u64, u64),
+
+    #[error("transition canceled: {0}")]
+    Canceled(S),
+
+    #[error("transition with event {0} is deferred")]
+    Deferred(S),
+
+    #[error("event {0} is queued until the current state accepts it")]
+    Queued(S),
+
+    #[error("event {0} inappropriate because previous transition did not complete")]
+    InTransition(S),
+
+    #[error("transition inappropriate because no state change in progress")]
+    NotInTransition,
+
+    #[error("event {0} is a duplicate")]
+    DuplicateEvent(S),
+
+    #[error(
+        "missing hooks: {}",
+        .0.iter().map(|h| h.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    MissingHooks(Vec<S>),
+
+    #[error("machine is poisoned")]
+    Poisoned,
+
+    #[error("machine finished in final state {0}")]
+    MachineFinished(S),
+
+    #[error("state {0} is not an entry point")]
+    InvalidEntryPoint(S),
+
+    #[error("event {0} is not allowed by this handle")]
+    NotAllowed(S),
+
+    #[error("machine is busy")]
+    Busy,
+
+    #[error("machine configuration is frozen")]
+    Frozen,
+
+    #[error("state {0} cannot be nested inside {1}")]
+    InvalidParent(S, S),
+
+    #[error("checkpoint {0} does not exist")]
+    NoCheckpoint(usize),
+
+    #[error("context differs from the one of checkpoint {0}")]
+    ContextMismatch(usize),
+
+    #[error("{} (args: {0})", .1.to_string())]
+    WithArgs(S, Box<FSMError<S>>),
+
+    #[error(
+        "{} (after hooks: {})",
+        .1.to_string(),
+        .0.iter().map(|h| h.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    WithTrace(Vec<S>, Box<FSMError<S>>),
+}
+
+/// ErrorKind categorizes an FSMError, e.g. for mapping it to a status code.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The event was understood but not allowed to change the state.
+    Rejected,
+    /// The event is not part of the machine definition.
+    Config,
+    /// A callback failed while handling the event.
+    Hook,
+    /// The event raced with, or was superseded by, another one.
+    Concurrency,
+    /// An unexpected failure inside the FSM itself.
+    Internal,
+}
+
+impl<S: Display> FSMError<S> {
+    /// kind returns the category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FSMError::NoTransitionWithError(_) => ErrorKind::Hook,
+            FSMError::NoTransition => ErrorKind::Rejected,
+            FSMError::InternalError(_) => ErrorKind::Hook,
+            FSMError::UnknownEvent(_) => ErrorKind::Config,
+            FSMError::InvalidEvent(_, _) => ErrorKind::Rejected,
+            FSMError::StaleEvent(_, _) => ErrorKind::Concurrency,
+            FSMError::Canceled(_) => ErrorKind::Rejected,
+            FSMError::Deferred(_) => ErrorKind::Concurrency,
+            FSMError::Queued(_) => ErrorKind::Concurrency,
+            FSMError::InTransition(_) => ErrorKind::Concurrency,
+            FSMError::NotInTransition => ErrorKind::Rejected,
+            FSMError::DuplicateEvent(_) => ErrorKind::Concurrency,
+            FSMError::MissingHooks(_) => ErrorKind::Config,
+            FSMError::Poisoned => ErrorKind::Internal,
+            FSMError::MachineFinished(_) => ErrorKind::Rejected,
+            FSMError::InvalidEntryPoint(_) => ErrorKind::Config,
+            FSMError::NotAllowed(_) => ErrorKind::Rejected,
+            FSMError::Busy => ErrorKind::Concurrency,
+            FSMError::Frozen => ErrorKind::Config,
+            FSMError::InvalidParent(_, _) => ErrorKind::Config,
+            FSMError::NoCheckpoint(_) => ErrorKind::Rejected,
+            FSMError::ContextMismatch(_) => ErrorKind::Rejected,
+            FSMError::WithArgs(_, err) => err.kind(),
+            FSMError::WithTrace(_, err) => err.kind(),
+        }
+    }
+
+    /// code returns a stable, machine readable identifier of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            FSMError::NoTransitionWithError(_) => "no_transition_with_error",
+            FSMError::NoTransition => "no_transition",
+            FSMError::InternalError(_) => "internal_error",
+            FSMError::UnknownEvent(_) => "unknown_event",
+            FSMError::InvalidEvent(_, _) => "invalid_event",
+            FSMError::StaleEvent(_, _) => "stale_event",
+            FSMError::Canceled(_) => "canceled",
+            FSMError::Deferred(_) => "deferred",
+            FSMError::Queued(_) => "queued",
+            FSMError::InTransition(_) => "in_transition",
+            FSMError::NotInTransition => "not_in_transition",
+            FSMError::DuplicateEvent(_) => "duplicate_event",
+            FSMError::MissingHooks(_) => "missing_hooks",
+            FSMError::Poisoned => "poisoned",
+            FSMError::MachineFinished(_) => "machine_finished",
+            FSMError::InvalidEntryPoint(_) => "invalid_entry_point",
+            FSMError::NotAllowed(_) => "not_allowed",
+            FSMError::Busy => "busy",
+            FSMError::Frozen => "frozen",
+            FSMError::InvalidParent(_, _) => "invalid_parent",
+            FSMError::NoCheckpoint(_) => "no_checkpoint",
+            FSMError::ContextMismatch(_) => "context_mismatch",
+            FSMError::WithArgs(_, err) => err.code(),
+            FSMError::WithTrace(_, err) => err.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorKind, FSMError};
+
+    #[test]
+    fn test_error_kind_and_code() {
+        let err: FSMError<String> = FSMError::UnknownEvent("open".to_string());
+        assert_eq!(ErrorKind::Config, err.kind());
+        assert_eq!("unknown_event", err.code());
+
+        let err: FSMError<&str> = FSMError::InvalidEvent("open", "opened");
+        assert_eq!(ErrorKind::Rejected, err.kind());
+        assert_eq!("invalid_event", err.code());
+
+        let err: FSMError<String> = FSMError::InternalError("boom".to_string());
+        assert_eq!(ErrorKind::Hook, err.kind());
+
+        let err: FSMError<String> = FSMError::StaleEvent(1, 2);
+        assert_eq!(ErrorKind::Concurrency, err.kind());
+
+        let err: FSMError<&str> = FSMError::WithArgs("[1]", Box::new(FSMError::NoTransition));
+        assert_eq!(ErrorKind::Rejected, err.kind());
+        assert_eq!("no_transition", err.code());
+        assert_eq!("no transition (args: [1])", err.to_string());
+    }
 }
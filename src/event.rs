@@ -1,6 +1,21 @@
+use crate::{
+    error::FSMError, fsm::CallbackType, metadata::Metadata, queue::Queue, resources::Resources,
+    sync::lock,
+};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
 /// Event is the info that get passed as a reference in the callbacks.
-pub struct Event<'a, S, I> {
-    /// `event` is the event name.
+pub struct Event<'a, S, I, C = ()> {
+    /// `event` is the event name. It is untyped because the FSM is not
+    /// generic over the event type, see is_event and parse_event.
     pub event: &'a str,
 
     /// `src` is the state before the transition.
@@ -11,4 +26,370 @@ pub struct Event<'a, S, I> {
 
     /// `args` is an optional list of arguments passed to the callback.
     pub args: Option<&'a I>,
+
+    // phase is the callback type currently being run.
+    phase: Cell<CallbackType>,
+
+    // dirty is set by callbacks that left external side effects half-applied.
+    dirty: Cell<bool>,
+
+    // deferred is set by a LeaveState callback that postpones the transition.
+    deferred: Cell<bool>,
+
+    // canceled holds the reason given by a callback that canceled the
+    // transition.
+    canceled: RefCell<Option<String>>,
+
+    // metadata is the metadata store of the FSM.
+    metadata: &'a Metadata,
+
+    // posted is the queue of events posted to the FSM.
+    posted: &'a Queue<I>,
+
+    // resources holds the resources of the states of the FSM.
+    resources: &'a Resources,
+
+    // context is the context of the FSM.
+    context: &'a Mutex<C>,
+
+    // data holds values attached by callbacks for the later stages of the
+    // same transition.
+    data: RefCell<HashMap<String, Rc<dyn Any>>>,
+
+    // sequence is the source sequence number of the event, if any.
+    sequence: Cell<Option<u64>>,
+
+    // error is the error the event failed with, for the OnError callback.
+    error: Cell<Option<&'a FSMError<String>>>,
+}
+
+impl<'a, S, I, C> Event<'a, S, I, C> {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        event: &'a str,
+        src: &'a S,
+        dst: &'a S,
+        args: Option<&'a I>,
+        metadata: &'a Metadata,
+        posted: &'a Queue<I>,
+        resources: &'a Resources,
+        context: &'a Mutex<C>,
+    ) -> Self {
+        Self {
+            event,
+            src,
+            dst,
+            args,
+            phase: Cell::new(CallbackType::None),
+            dirty: Cell::new(false),
+            deferred: Cell::new(false),
+            canceled: RefCell::new(None),
+            metadata,
+            posted,
+            resources,
+            context,
+            data: RefCell::default(),
+            sequence: Cell::new(None),
+            error: Cell::new(None),
+        }
+    }
+
+    /// phase returns the type of the callback currently handling the event,
+    /// so one handler registered for several hook types can tell them apart.
+    pub fn phase(&self) -> CallbackType {
+        self.phase.get()
+    }
+
+    pub(crate) fn set_phase(&self, phase: CallbackType) {
+        self.phase.set(phase);
+    }
+
+    /// sequence returns the source sequence number of the event, if it was
+    /// fired as Sequenced.
+    pub fn sequence(&self) -> Option<u64> {
+        self.sequence.get()
+    }
+
+    pub(crate) fn set_sequence(&self, sequence: Option<u64>) {
+        self.sequence.set(sequence);
+    }
+
+    /// error returns the error the event failed with, in an OnError callback.
+    pub fn error(&self) -> Option<&'a FSMError<String>> {
+        self.error.get()
+    }
+
+    pub(crate) fn set_error(&self, error: &'a FSMError<String>) {
+        self.error.set(Some(error));
+    }
+
+    /// is_event returns true if the event name equals event, so callbacks can
+    /// compare against an enum variant instead of a string literal.
+    pub fn is_event<T: AsRef<str>>(&self, event: T) -> bool {
+        self.event == event.as_ref()
+    }
+
+    /// parse_event parses the event name back into the event type, so
+    /// callbacks can match on its variants. It returns None if the name does
+    /// not parse, e.g. for the empty event of set_state.
+    pub fn parse_event<T: FromStr>(&self) -> Option<T> {
+        self.event.parse().ok()
+    }
+
+    /// is_self_transition returns true if the source and destination states
+    /// are the same.
+    pub fn is_self_transition(&self) -> bool
+    where
+        S: PartialEq,
+    {
+        self.src == self.dst
+    }
+
+    /// to_record returns an owned copy of the event name and states, which
+    /// can outlive the callback.
+    pub fn to_record(&self) -> EventRecord<S>
+    where
+        S: Clone,
+    {
+        EventRecord {
+            event: self.event.to_string(),
+            src: self.src.clone(),
+            dst: self.dst.clone(),
+        }
+    }
+
+    /// cancel cancels the transition from a BeforeEvent or LeaveState
+    /// callback. The FSM stays in its current state, no further callbacks are
+    /// run and on_event fails with FSMError::Canceled(reason).
+    //
+    // Canceling from EnterState or AfterEvent callbacks has no effect, since
+    // the transition already happened.
+    pub fn cancel<R: Into<String>>(&self, reason: R) {
+        self.canceled.replace(Some(reason.into()));
+    }
+
+    /// is_canceled returns true if a callback canceled the transition.
+    pub fn is_canceled(&self) -> bool {
+        self.canceled.borrow().is_some()
+    }
+
+    pub(crate) fn take_cancel_reason(&self) -> Option<String> {
+        self.canceled.take()
+    }
+
+    /// defer postpones the transition from a LeaveState callback. on_event
+    /// then fails with FSMError::Deferred and the FSM stays in its current
+    /// state until FSM::transition is called.
+    pub fn defer(&self) {
+        self.deferred.set(true);
+    }
+
+    /// is_deferred returns true if a callback deferred the transition.
+    pub fn is_deferred(&self) -> bool {
+        self.deferred.get()
+    }
+
+    /// mark_dirty signals that the callback left external side effects
+    /// half-applied. If the callback then fails and poison mode is enabled,
+    /// the FSM is poisoned.
+    pub fn mark_dirty(&self) {
+        self.dirty.set(true);
+    }
+
+    /// is_dirty returns true if a callback called mark_dirty.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.get()
+    }
+
+    /// post queues event to be processed by FSM::dispatch once the current
+    /// transition has completed.
+    pub fn post<T: AsRef<str>>(&self, event: T, args: Option<I>) {
+        self.post_with_priority(event, 0, args);
+    }
+
+    /// post_with_priority is like post, but gives the event a priority, see
+    /// FSM::post_with_priority.
+    pub fn post_with_priority<T: AsRef<str>>(&self, event: T, priority: i32, args: Option<I>) {
+        self.posted.push(event.as_ref().to_string(), args, priority);
+    }
+
+    /// hold keeps resource, e.g. a connection or a guard, until the FSM
+    /// leaves the destination state of the event, and then drops it.
+    //
+    // Resources are dropped newest first. They belong to the destination
+    // state, so one held by an EnterState callback of an ancestor is dropped
+    // as soon as the nested destination is left.
+    pub fn hold<T: Send + 'static>(&self, resource: T)
+    where
+        S: AsRef<str>,
+    {
+        self.resources.hold(self.dst.as_ref(), resource);
+    }
+
+    /// once runs the side effect f, identified by id, at most once per
+    /// sequenced event, so replaying an event log to rebuild the FSM does not
+    /// run it again. It returns true if f ran.
+    //
+    // The effect is recorded before f runs, see FSM::get_effects, until
+    // FSM::ack_effects forgets it. Events without a sequence number are not
+    // tracked: f always runs, except in a sandbox, where it never does.
+    pub fn once<K: Into<String>, G: FnOnce()>(&self, id: K, f: G) -> bool {
+        if self.metadata.is_sandboxed() {
+            return false;
+        }
+        if let Some(seq) = self.sequence.get() {
+            if !self.metadata.record_effect(seq, id.into()) {
+                return false;
+            }
+        }
+        f();
+        true
+    }
+
+    /// set_data attaches value to the event under key, so callbacks of later
+    /// stages of the same transition can read it with get_data.
+    //
+    // Data lives as long as the event: it is not kept across transitions,
+    // nor passed on to the callbacks run by FSM::transition after a deferral.
+    pub fn set_data<K: Into<String>, T: Any>(&self, key: K, value: T) {
+        self.data.borrow_mut().insert(key.into(), Rc::new(value));
+    }
+
+    /// get_data returns the value attached under key, if any and if it is a T.
+    pub fn get_data<T: Any>(&self, key: &str) -> Option<Rc<T>> {
+        self.data.borrow().get(key)?.clone().downcast().ok()
+    }
+
+    /// get_context locks and returns the context of the FSM, so callbacks
+    /// can update it.
+    //
+    // The guard must be dropped before the callback returns: the FSM and the
+    // later callbacks lock the context too.
+    pub fn get_context(&self) -> MutexGuard<'a, C> {
+        lock(self.context)
+    }
+
+    /// set_metadata stores value under key in the metadata of the FSM, where
+    /// later callbacks and FSM::get_metadata can read it.
+    pub fn set_metadata<K: Into<String>, T: Any + Send + Sync>(&self, key: K, value: T) {
+        self.metadata.set(key.into(), value);
+    }
+
+    /// get_metadata returns the metadata value stored under key, if any and
+    /// if it is a T.
+    pub fn get_metadata<T: Any + Send + Sync>(&self, key: &str) -> Option<Arc<T>> {
+        self.metadata.get(key)
+    }
+
+    /// delete_metadata removes the metadata value stored under key, and
+    /// returns true if there was one.
+    pub fn delete_metadata(&self, key: &str) -> bool {
+        self.metadata.delete(key)
+    }
+}
+
+/// AsEvent is what FSM::on_event accepts as an event: a name that is
+/// `AsRef<str>`, a name given as raw bytes with Bytes, or either of them
+/// stamped with a source sequence number with Sequenced.
+pub trait AsEvent {
+    /// name returns the event name.
+    fn name(&self) -> Result<&str, FSMError<String>>;
+
+    /// sequence returns the source sequence number of the event, if any.
+    fn sequence(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<T: AsRef<str>> AsEvent for T {
+    fn name(&self) -> Result<&str, FSMError<String>> {
+        Ok(self.as_ref())
+    }
+}
+
+/// Bytes is an event named by raw bytes, e.g. a token sliced out of a network
+/// buffer, which is dispatched without copying it.
+//
+// A name that is not valid UTF-8 cannot match any event: on_event fails with
+// FSMError::UnknownEvent before the FSM sees it.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Bytes<'b>(pub &'b [u8]);
+
+impl<'b> AsEvent for Bytes<'b> {
+    fn name(&self) -> Result<&str, FSMError<String>> {
+        std::str::from_utf8(self.0)
+            .map_err(|_| FSMError::UnknownEvent(String::from_utf8_lossy(self.0).into_owned()))
+    }
+}
+
+/// Sequenced stamps an event with its source sequence number, e.g.
+/// `Sequenced(7, "open")`, when the FSM is driven from an event stream.
+//
+// Events whose sequence is at or below the last applied one are rejected
+// with FSMError::StaleEvent, so a partially consumed stream can be safely
+// replayed from the beginning. The sequence only advances when the
+// transition succeeds.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Sequenced<T>(pub u64, pub T);
+
+impl<T: AsEvent> AsEvent for Sequenced<T> {
+    fn name(&self) -> Result<&str, FSMError<String>> {
+        self.1.name()
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        Some(self.0)
+    }
+}
+
+/// EventRecord is an owned copy of an Event, without its args.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct EventRecord<S> {
+    /// `event` is the event name.
+    pub event: String,
+
+    /// `src` is the state before the transition.
+    pub src: S,
+
+    /// `dst` is the state after the transition.
+    pub dst: S,
+}
+
+/// EntryInfo describes the last transition into a state, see
+/// FSM::last_entry_info.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct EntryInfo<S> {
+    /// `record` is the transition that entered the state.
+    pub record: EventRecord<S>,
+
+    /// `args` is the summary of the args of the transition, if it had any.
+    pub args: Option<String>,
+}
+
+/// HistoryEntry is an event kept in the history of an FSM, see
+/// FSM::history.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct HistoryEntry<S> {
+    /// `at` is when the event was handled, on the clock driven by
+    /// FSM::tick. It only advances when tick is called; see time for the
+    /// wall-clock time.
+    pub at: Duration,
+
+    /// `time` is the wall-clock time at which the event was handled.
+    pub time: SystemTime,
+
+    /// `record` is the transition, from the state before the event to the
+    /// state after it.
+    pub record: EventRecord<S>,
+
+    /// `error` describes why the event failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Summarize produces a short, redacted description of event arguments.
+///
+/// It is used when arguments are attached to errors, so implementations should
+/// leave out anything sensitive.
+pub trait Summarize {
+    fn summarize(&self) -> String;
 }
@@ -1,5 +1,24 @@
-use crate::{action::Action, error::FSMError, event::Event};
-use std::{borrow::Cow, collections::HashMap, fmt::Display};
+use crate::{
+    action::Action,
+    dedup::Dedup,
+    error::{ErrorKind, FSMError},
+    event::{AsEvent, EntryInfo, Event, EventRecord, HistoryEntry, Summarize},
+    metadata::Metadata,
+    policy::{FailurePolicy, Fallback, HookPolicy, NoTransitionPolicy, QueuePolicy},
+    profiler::Profiler,
+    queue::{Queue, Queued},
+    resources::{Released, Resources},
+    sync::lock,
+};
+use std::{
+    any::Any,
+    borrow::{Borrow, Cow},
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::{self, Debug, Display},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
 
 /// FSMState represents the state of the FSM.
 pub trait FSMState: AsRef<Self> + AsRef<str> + Display + Clone + Eq + PartialEq {}
@@ -12,21 +31,60 @@ pub enum HookType<T: AsRef<str>, S: FSMState> {
     Leave(S),
     Enter(S),
     Custom(&'static str),
+    /// `Transition(event, src)` is the action run when event moves the FSM
+    /// out of src, after the LeaveState and before the EnterState callbacks.
+    /// It also runs from the descendants of src that inherit the transition.
+    Transition(T, S),
+    /// `TransitionFromAny(event)` is the action of the transition declared
+    /// for event from any state, see Transition.
+    TransitionFromAny(T),
+    /// `EnterGroup(group)` runs when the FSM enters a state of group from a
+    /// state outside of it, see FSM::set_group.
+    EnterGroup(&'static str),
+    /// `LeaveGroup(group)` runs when the FSM leaves a state of group for a
+    /// state outside of it.
+    LeaveGroup(&'static str),
 
     BeforeEvent,
     AfterEvent,
     LeaveState,
     EnterState,
+    /// `OnError` runs when on_event fails with FSMError::UnknownEvent,
+    /// FSMError::InvalidEvent or a callback failure. Event::error returns the
+    /// error, and the event src and dst are both the current state.
+    OnError,
+    /// `Any` runs at every stage of every transition, after the other
+    /// callbacks of the stage. Event::phase tells the stages apart.
+    Any,
 }
 
 /// CallbackType represents the type of callback.
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum CallbackType {
     None,
     BeforeEvent,
     LeaveState,
+    Transition,
     EnterState,
     AfterEvent,
+    OnError,
+    Any,
+}
+
+impl Display for CallbackType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CallbackType::None => "none",
+            CallbackType::BeforeEvent => "before_event",
+            CallbackType::LeaveState => "leave_state",
+            CallbackType::Transition => "transition",
+            CallbackType::EnterState => "enter_state",
+            CallbackType::AfterEvent => "after_event",
+            CallbackType::OnError => "on_error",
+            CallbackType::Any => "any",
+        };
+        f.write_str(name)
+    }
 }
 
 /// EventDesc represents an event when initializing the FSM.
@@ -44,7 +102,7 @@ where
     pub name: T,
 
     /// `src` is a slice of source states that the FSM must be in to perform a
-    /// state transition.
+    /// state transition. An empty `src` means any state.
     pub src: Vec<S>,
 
     /// `dst` is the destination state that the FSM will be in if the transition
@@ -52,97 +110,611 @@ where
     pub dst: S,
 }
 
+/// IntoEventDesc converts a value into the parts of an EventDesc: the event
+/// name, its source states and its destination state.
+///
+/// Besides EventDesc, it is implemented for `(name, src, dst)` and
+/// `(name, &[src], dst)` tuples, so machines can be built from data tables
+/// without creating EventDesc values first.
+pub trait IntoEventDesc<S> {
+    /// `Name` is the type of the event name.
+    type Name: AsRef<str>;
+
+    /// `Src` iterates over the source states.
+    type Src: IntoIterator<Item = Self::SrcItem>;
+
+    /// `SrcItem` is a source state, either owned or borrowed.
+    type SrcItem: Borrow<S>;
+
+    /// into_parts splits the value into name, source states and destination.
+    fn into_parts(self) -> (Self::Name, Self::Src, S);
+}
+
+impl<T, S> IntoEventDesc<S> for EventDesc<T, S>
+where
+    T: AsRef<str>,
+    S: FSMState,
+{
+    type Name = T;
+    type Src = Vec<S>;
+    type SrcItem = S;
+
+    fn into_parts(self) -> (T, Vec<S>, S) {
+        (self.name, self.src, self.dst)
+    }
+}
+
+impl<T, S> IntoEventDesc<S> for (T, S, S)
+where
+    T: AsRef<str>,
+{
+    type Name = T;
+    type Src = std::iter::Once<S>;
+    type SrcItem = S;
+
+    fn into_parts(self) -> (T, std::iter::Once<S>, S) {
+        (self.0, std::iter::once(self.1), self.2)
+    }
+}
+
+impl<'s, T, S> IntoEventDesc<S> for (T, &'s [S], S)
+where
+    T: AsRef<str>,
+{
+    type Name = T;
+    type Src = &'s [S];
+    type SrcItem = &'s S;
+
+    fn into_parts(self) -> (T, &'s [S], S) {
+        self
+    }
+}
+
+/// PruneReport lists what prune_unreachable removed from the FSM.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    /// `states` are the removed states, sorted by name.
+    pub states: Vec<String>,
+
+    /// `transitions` are the removed `(event, src)` pairs, sorted.
+    pub transitions: Vec<(String, String)>,
+}
+
 /// EKey is a struct key used for storing the transition map.
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 struct EKey<'a> {
     // event is the name of the event that the keys refers to.
     event: Cow<'a, str>,
 
-    // src is the source from where the event can transition.
+    // src is the source from where the event can transition. It is "" for a
+    // wildcard transition that applies in any state.
     src: Cow<'a, str>,
 }
 
+impl<'a> EKey<'a> {
+    // src_label returns the source for display, "*" for a wildcard.
+    fn src_label(&self) -> &str {
+        if self.src.is_empty() {
+            "*"
+        } else {
+            &self.src
+        }
+    }
+}
+
 /// CKey is a struct key used for keeping the callbacks mapped to a target.
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+//
+// Keys are ordered by callback type first, so callbacks are listed by
+// lifecycle stage.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 struct CKey<'a> {
-    // target is either the name of a state or an event depending on which
-    // callback type the key refers to. It can also be "" for a non-targeted
-    // callback like before_event.
-    target: Cow<'a, str>,
-
     // callback_type is the situation when the callback will be run.
     callback_type: CallbackType,
+
+    // target is what the callback is registered for.
+    target: Target<'a>,
+}
+
+impl<'a> Display for CKey<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.target == Target::any() {
+            write!(f, "{}", self.callback_type)
+        } else {
+            write!(f, "{}({})", self.callback_type, self.target)
+        }
+    }
+}
+
+/// Target is what a callback is registered for.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+enum Target<'a> {
+    // Name is the name of a state or an event depending on which callback
+    // type the key refers to. It can also be "" for a non-targeted callback
+    // like before_event.
+    Name(Cow<'a, str>),
+    // Group is a group of states, see FSM::set_group.
+    Group(Cow<'a, str>),
+    // Transition is an event and the source state it is declared from, ""
+    // for the transitions declared from any state.
+    Transition(Cow<'a, str>, Cow<'a, str>),
+}
+
+impl<'a> Target<'a> {
+    // any returns the target of a non-targeted callback.
+    fn any() -> Self {
+        Target::Name(Cow::Borrowed(""))
+    }
+}
+
+impl<'a> Display for Target<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Name(name) => write!(f, "{}", name),
+            Target::Group(group) => write!(f, "group:{}", group),
+            Target::Transition(event, src) if src.is_empty() => write!(f, "{}:*", event),
+            Target::Transition(event, src) => write!(f, "{}:{}", event, src),
+        }
+    }
+}
+
+/// MAX_ENTRY_SUMMARY is the maximum length in bytes of the args summary kept
+/// by FSM::last_entry_info.
+const MAX_ENTRY_SUMMARY: usize = 256;
+
+/// DEFAULT_CHECKPOINT_CAPACITY is the number of checkpoints kept by default.
+const DEFAULT_CHECKPOINT_CAPACITY: usize = 16;
+
+/// DEFAULT_FORCED_CAPACITY is the number of forced transitions kept by
+/// default.
+const DEFAULT_FORCED_CAPACITY: usize = 16;
+
+/// Checkpoint is a snapshot of the runtime state of the FSM.
+struct Checkpoint<S, I> {
+    current: S,
+    sequence: Option<u64>,
+    pending: Option<Pending<S>>,
+    queued: VecDeque<Queued<I>>,
+    // context is the hash of the context, which is not saved itself.
+    context: u64,
 }
 
+impl<S: Clone, I> Clone for Checkpoint<S, I> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current.clone(),
+            sequence: self.sequence,
+            pending: self.pending.clone(),
+            queued: self.queued.clone(),
+            context: self.context,
+        }
+    }
+}
+
+/// Pending is a transition deferred by a LeaveState callback.
+#[derive(Debug, Clone)]
+struct Pending<S> {
+    event: String,
+    src: S,
+    dst: S,
+    // declared is the source of the transition that matched, see hook_set.
+    declared: String,
+}
+
+/// Transaction buffers the EnterState and AfterEvent callbacks of the steps
+/// taken by transact until it commits.
+struct Transaction<S, I> {
+    // clone clones the args of a step, so the FSM does not need I: Clone
+    // itself.
+    clone: fn(&I) -> I,
+    steps: Vec<Step<S, I>>,
+}
+
+/// Step is an event applied within a transaction, whose callbacks have not
+/// run yet.
+struct Step<S, I> {
+    kind: StepKind,
+    event: String,
+    src: S,
+    dst: S,
+    args: Option<I>,
+    sequence: Option<u64>,
+    enter_state: Vec<usize>,
+    after_event: Vec<usize>,
+}
+
+/// StepKind tells how a Step was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepKind {
+    // Entered moved the FSM into dst, leaving the states that are not its
+    // ancestors.
+    Entered,
+    // Started entered the current state, see start.
+    Started,
+    // Stayed left the FSM where it was, per the no transition policy; its
+    // AfterEvent callbacks fail it like on_event does.
+    Stayed,
+}
+
+/// HookSet holds the positions in the callback table of the callbacks that
+/// fire during one transition, in the order they run, for each callback type.
+#[derive(Debug, Clone, Default)]
+struct HookSet {
+    before_event: Vec<usize>,
+    leave_state: Vec<usize>,
+    transition: Vec<usize>,
+    enter_state: Vec<usize>,
+    after_event: Vec<usize>,
+}
+
+/// HookEntries maps source states, then events, to the declared source and
+/// destination the hooks were resolved for and the resolved hooks.
+type HookEntries = HashMap<String, HashMap<String, (String, String, Arc<HookSet>)>>;
+
+/// HookCache memoizes the HookSet of each source state and event pair.
+#[derive(Debug, Clone, Default)]
+struct HookCache {
+    entries: Arc<HookEntries>,
+    hits: u64,
+    misses: u64,
+}
+
+/// HookCacheStats reports how well the hook resolution cache performs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookCacheStats {
+    /// hits is the number of transitions whose hooks came from the cache.
+    pub hits: u64,
+    /// misses is the number of transitions whose hooks had to be resolved.
+    pub misses: u64,
+    /// entries is the number of (state, event) pairs currently cached.
+    pub entries: usize,
+}
+
+/// Timer is an event scheduled to fire once the clock reaches due.
+struct Timer<I> {
+    due: Duration,
+    queued: Queued<I>,
+}
+
+impl<I> Clone for Timer<I> {
+    fn clone(&self) -> Self {
+        Self {
+            due: self.due,
+            queued: self.queued.clone(),
+        }
+    }
+}
+
+/// Transformer rewrites the args of an event before callbacks see them.
+type Transformer<'a, I> = Arc<dyn Fn(&I) -> I + Send + Sync + 'a>;
+
+/// Chooser picks the destination of an event from the source state and args.
+type Chooser<'a, S, I> = Arc<dyn Fn(&S, Option<&I>) -> S + Send + Sync + 'a>;
+
+/// FallbackHandler decides what to do with an event that has no transition
+/// from the current state.
+type FallbackHandler<'a, S> = Arc<dyn Fn(&S, &str) -> Fallback + Send + Sync + 'a>;
+
 /// FSM represents a finite state machine.
 ///
 /// The FSM is initialized with an initial state and a list of events.
 ///
-#[derive(Debug, Clone)]
-pub struct FSM<'a, S, I, F: Action<S, I>> {
+/// The transition and callback tables are shared between clones, so cloning an
+/// FSM, e.g. for a dry run, only copies its runtime state.
+///
+/// Transitions are kept ordered by event and source state, and callbacks by
+/// lifecycle stage and then by target, so Debug output, describe and analysis
+/// results are stable across runs.
+pub struct FSM<'a, S, I, F: Action<S, I, C>, C = ()> {
     _marker: std::marker::PhantomData<I>,
 
     // current is the state that the FSM is currently in.
     current: S,
 
     // transitions maps events and source states to destination states.
-    transitions: HashMap<EKey<'a>, S>,
+    transitions: Arc<BTreeMap<EKey<'a>, S>>,
+
+    // callbacks holds the callback functions sorted by key, so they can be
+    // looked up by binary search and referred to by position.
+    callbacks: Arc<Vec<(CKey<'a>, F)>>,
+
+    // hook_cache memoizes which callbacks fire per (state, event).
+    hook_cache: HookCache,
+
+    // transformers maps events to the transformer applied to their args.
+    transformers: Arc<BTreeMap<String, Transformer<'a, I>>>,
+
+    // choosers maps events to the function choosing their destination.
+    choosers: Arc<BTreeMap<String, Chooser<'a, S, I>>>,
+
+    // frozen is set by freeze_hooks.
+    frozen: bool,
+
+    // fallback handles the events that have no transition from the current
+    // state, if set.
+    fallback: Option<FallbackHandler<'a, S>>,
+
+    // parents maps states to their parent state, see set_parent.
+    parents: Arc<HashMap<String, String>>,
+
+    // groups maps states to the groups they are tagged with, see set_group.
+    groups: Arc<HashMap<String, BTreeSet<String>>>,
+
+    // finals holds the final states, see set_final.
+    finals: Arc<HashSet<String>>,
+
+    // externals holds the events whose self-transitions are external, see
+    // set_external.
+    externals: Arc<HashSet<String>>,
+
+    // deferrable holds the events that are queued instead of rejected in a
+    // state, see set_deferrable.
+    deferrable: Arc<BTreeSet<EKey<'a>>>,
+
+    // queued holds the deferrable events waiting to be replayed, oldest
+    // first.
+    queued: VecDeque<Queued<I>>,
+
+    // sequence is the source sequence number of the last applied event.
+    sequence: Option<u64>,
+
+    // dedup suppresses identical events within a window, if enabled.
+    dedup: Option<Dedup<I>>,
+
+    // profiler records the time spent in each hook, if enabled.
+    profiler: Option<Profiler>,
+
+    // summarize summarizes args, once set_entry_capture or set_error_summary
+    // enabled it.
+    summarize: Option<fn(&I) -> String>,
+
+    // capture_entries enables keeping entries, see set_entry_capture.
+    capture_entries: bool,
+
+    // summarize_errors enables attaching args summaries to errors, see
+    // set_error_summary.
+    summarize_errors: bool,
+
+    // clone_args clones the args of deferred events into queued, once
+    // set_deferrable enabled it.
+    clone_args: Option<fn(&I) -> I>,
+
+    // entries maps states to the last transition into them.
+    entries: HashMap<String, EntryInfo<S>>,
+
+    // poison_on_dirty enables poisoning the FSM when a failing callback has
+    // marked the event dirty.
+    poison_on_dirty: bool,
+
+    // poisoned is set once the FSM is poisoned, and rejects all events until
+    // repaired.
+    poisoned: bool,
+
+    // hook_policy decides how callback failures are handled.
+    hook_policy: HookPolicy,
+
+    // checkpoints holds the most recent checkpoints, newest last.
+    checkpoints: VecDeque<Checkpoint<S, I>>,
+
+    // checkpoint_capacity is the maximum number of checkpoints kept.
+    checkpoint_capacity: usize,
+
+    // sandboxed disables all callbacks but the guards, see sandbox.
+    sandboxed: bool,
+
+    // transaction is the transaction opened by transact, if any.
+    transaction: Option<Transaction<S, I>>,
+
+    // pending is the transition deferred by a LeaveState callback, if any.
+    pending: Option<Pending<S>>,
+
+    // previous is the source state of the last transition, see rollback.
+    previous: Option<S>,
+
+    // forced holds the last forced_capacity transitions performed by
+    // force_event, see set_forced_capacity.
+    forced: VecDeque<EventRecord<S>>,
+    forced_capacity: usize,
+
+    // history holds the last history_capacity events, see
+    // set_history_capacity.
+    history: VecDeque<HistoryEntry<S>>,
+    history_capacity: usize,
 
-    // callbacks maps events and targets to callback functions.
-    callbacks: HashMap<CKey<'a>, F>,
+    // metadata holds per-instance data shared with callbacks.
+    metadata: Arc<Metadata>,
+
+    // context is the extended state of the FSM, see set_context.
+    context: Arc<Mutex<C>>,
+
+    // posted holds the events posted with post or Event::post, see dispatch.
+    posted: Arc<Queue<I>>,
+
+    // queue_policy decides the order in which posted events are dispatched.
+    queue_policy: QueuePolicy,
+
+    // no_transition_policy decides what a self-transition does.
+    no_transition_policy: NoTransitionPolicy,
+
+    // resources holds the resources callbacks acquired for the current state
+    // and its ancestors, see Event::hold.
+    resources: Arc<Resources>,
+
+    // clock is the time elapsed according to tick.
+    clock: Duration,
+
+    // timers holds the scheduled events, ordered by due time.
+    timers: Vec<Timer<I>>,
+}
+
+impl<'a, S, I, F, C> Clone for FSM<'a, S, I, F, C>
+where
+    S: Clone,
+    F: Action<S, I, C>,
+    C: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            _marker: std::marker::PhantomData,
+            current: self.current.clone(),
+            transitions: self.transitions.clone(),
+            callbacks: self.callbacks.clone(),
+            hook_cache: self.hook_cache.clone(),
+            transformers: self.transformers.clone(),
+            choosers: self.choosers.clone(),
+            frozen: self.frozen,
+            fallback: self.fallback.clone(),
+            parents: self.parents.clone(),
+            groups: self.groups.clone(),
+            finals: self.finals.clone(),
+            externals: self.externals.clone(),
+            deferrable: self.deferrable.clone(),
+            queued: self.queued.clone(),
+            sequence: self.sequence,
+            dedup: self.dedup.clone(),
+            profiler: self.profiler.clone(),
+            summarize: self.summarize,
+            capture_entries: self.capture_entries,
+            summarize_errors: self.summarize_errors,
+            clone_args: self.clone_args,
+            entries: self.entries.clone(),
+            poison_on_dirty: self.poison_on_dirty,
+            poisoned: self.poisoned,
+            hook_policy: self.hook_policy.clone(),
+            checkpoints: self.checkpoints.clone(),
+            checkpoint_capacity: self.checkpoint_capacity,
+            sandboxed: self.sandboxed,
+            transaction: None,
+            pending: self.pending.clone(),
+            previous: self.previous.clone(),
+            forced: self.forced.clone(),
+            forced_capacity: self.forced_capacity,
+            history: self.history.clone(),
+            history_capacity: self.history_capacity,
+            metadata: Arc::new(Metadata::clone(&self.metadata)),
+            context: Arc::new(Mutex::new(lock(&self.context).clone())),
+            posted: Arc::new(Queue::clone(&self.posted)),
+            queue_policy: self.queue_policy,
+            no_transition_policy: self.no_transition_policy,
+            resources: Arc::default(),
+            clock: self.clock,
+            timers: self.timers.clone(),
+        }
+    }
 }
 
 impl<'a, S, I, F> FSM<'a, S, I, F>
 where
     S: FSMState,
-    I: IntoIterator,
     F: Action<S, I>,
 {
     /// new creates a new FSM.
-    pub fn new<T>(
+    //
+    // Events can be given as EventDesc values or as (name, src, dst) and
+    // (name, &[src], dst) tuples, see IntoEventDesc.
+    pub fn new<E>(
         initial: S,
-        events: impl IntoIterator<Item = EventDesc<T, S>>,
-        hooks: impl IntoIterator<Item = (HookType<T, S>, F)>,
+        events: impl IntoIterator<Item = E>,
+        hooks: impl IntoIterator<Item = (HookType<E::Name, S>, F)>,
     ) -> Self
     where
-        T: AsRef<str>,
+        E: IntoEventDesc<S>,
+    {
+        Self::new_with_context(initial, events, hooks, ())
+    }
+}
+
+impl<'a, S, I, F, C> FSM<'a, S, I, F, C>
+where
+    S: FSMState,
+    F: Action<S, I, C>,
+{
+    /// new_with_context is like new, but gives the FSM a context, its
+    /// extended state, which the callbacks can update, see
+    /// Event::get_context.
+    pub fn new_with_context<E>(
+        initial: S,
+        events: impl IntoIterator<Item = E>,
+        hooks: impl IntoIterator<Item = (HookType<E::Name, S>, F)>,
+        context: C,
+    ) -> Self
+    where
+        E: IntoEventDesc<S>,
     {
         let mut all_events = HashMap::new();
         let mut all_states = HashMap::new();
-        let mut transitions = HashMap::new();
+        let mut transitions = BTreeMap::new();
 
         for e in events {
-            all_events.insert(e.name.as_ref().to_string(), true);
-            for src in e.src.iter() {
+            let (name, src, dst) = e.into_parts();
+            all_events.insert(name.as_ref().to_string(), true);
+            all_states.insert(dst.to_string(), true);
+            let mut src = src.into_iter().peekable();
+            if src.peek().is_none() {
+                transitions.insert(
+                    EKey {
+                        event: Cow::Owned(name.as_ref().to_string()),
+                        src: Cow::Borrowed(""),
+                    },
+                    dst.clone(),
+                );
+            }
+            for src in src {
+                let src = src.borrow();
                 transitions.insert(
                     EKey {
-                        event: Cow::Owned(e.name.as_ref().to_string()),
+                        event: Cow::Owned(name.as_ref().to_string()),
                         src: Cow::Owned(src.to_string()),
                     },
-                    e.dst.clone(),
+                    dst.clone(),
                 );
                 all_states.insert(src.to_string(), true);
-                all_states.insert(e.dst.to_string(), true);
+                all_states.insert(dst.to_string(), true);
             }
         }
 
-        let mut callbacks: HashMap<CKey, F> = HashMap::new();
+        let mut callbacks: BTreeMap<CKey, F> = BTreeMap::new();
         for (name, callback) in hooks {
             let (target, callback_type) = match name {
-                HookType::BeforeEvent => (Cow::Borrowed(""), CallbackType::BeforeEvent),
-                HookType::AfterEvent => (Cow::Borrowed(""), CallbackType::AfterEvent),
+                HookType::BeforeEvent => (Target::any(), CallbackType::BeforeEvent),
+                HookType::AfterEvent => (Target::any(), CallbackType::AfterEvent),
                 HookType::Before(t) => (
-                    Cow::Owned(t.as_ref().to_string()),
+                    Target::Name(Cow::Owned(t.as_ref().to_string())),
                     CallbackType::BeforeEvent,
                 ),
-                HookType::After(t) => {
-                    (Cow::Owned(t.as_ref().to_string()), CallbackType::AfterEvent)
-                }
+                HookType::After(t) => (
+                    Target::Name(Cow::Owned(t.as_ref().to_string())),
+                    CallbackType::AfterEvent,
+                ),
 
-                HookType::LeaveState => (Cow::Borrowed(""), CallbackType::LeaveState),
-                HookType::EnterState => (Cow::Borrowed(""), CallbackType::EnterState),
-                HookType::Leave(t) => (Cow::Owned(t.to_string()), CallbackType::LeaveState),
-                HookType::Enter(t) => (Cow::Owned(t.to_string()), CallbackType::EnterState),
+                HookType::LeaveState => (Target::any(), CallbackType::LeaveState),
+                HookType::EnterState => (Target::any(), CallbackType::EnterState),
+                HookType::OnError => (Target::any(), CallbackType::OnError),
+                HookType::Any => (Target::any(), CallbackType::Any),
+                HookType::Leave(t) => (
+                    Target::Name(Cow::Owned(t.to_string())),
+                    CallbackType::LeaveState,
+                ),
+                HookType::Enter(t) => (
+                    Target::Name(Cow::Owned(t.to_string())),
+                    CallbackType::EnterState,
+                ),
+                HookType::Transition(t, s) => (
+                    Target::Transition(
+                        Cow::Owned(t.as_ref().to_string()),
+                        Cow::Owned(s.to_string()),
+                    ),
+                    CallbackType::Transition,
+                ),
+                HookType::TransitionFromAny(t) => (
+                    Target::Transition(Cow::Owned(t.as_ref().to_string()), Cow::Borrowed("")),
+                    CallbackType::Transition,
+                ),
+                HookType::EnterGroup(g) => {
+                    (Target::Group(Cow::Borrowed(g)), CallbackType::EnterState)
+                }
+                HookType::LeaveGroup(g) => {
+                    (Target::Group(Cow::Borrowed(g)), CallbackType::LeaveState)
+                }
 
                 HookType::Custom(t) => {
                     let callback_type = if all_states.contains_key(t) {
@@ -152,7 +724,7 @@ where
                     } else {
                         CallbackType::None
                     };
-                    (Cow::Borrowed(t), callback_type)
+                    (Target::Name(Cow::Borrowed(t)), callback_type)
                 }
             };
 
@@ -169,9 +741,68 @@ where
         Self {
             _marker: std::marker::PhantomData,
             current: initial,
-            callbacks,
-            transitions,
+            callbacks: Arc::new(callbacks.into_iter().collect()),
+            hook_cache: HookCache::default(),
+            transitions: Arc::new(transitions),
+            transformers: Arc::new(BTreeMap::new()),
+            choosers: Arc::new(BTreeMap::new()),
+            frozen: false,
+            fallback: None,
+            parents: Arc::new(HashMap::new()),
+            groups: Arc::new(HashMap::new()),
+            finals: Arc::new(HashSet::new()),
+            externals: Arc::new(HashSet::new()),
+            deferrable: Arc::new(BTreeSet::new()),
+            queued: VecDeque::new(),
+            sequence: None,
+            dedup: None,
+            profiler: None,
+            summarize: None,
+            capture_entries: false,
+            summarize_errors: false,
+            clone_args: None,
+            entries: HashMap::new(),
+            poison_on_dirty: false,
+            poisoned: false,
+            hook_policy: HookPolicy::default(),
+            checkpoints: VecDeque::new(),
+            checkpoint_capacity: DEFAULT_CHECKPOINT_CAPACITY,
+            sandboxed: false,
+            transaction: None,
+            pending: None,
+            previous: None,
+            forced: VecDeque::new(),
+            forced_capacity: DEFAULT_FORCED_CAPACITY,
+            history: VecDeque::new(),
+            history_capacity: 0,
+            metadata: Arc::default(),
+            context: Arc::new(Mutex::new(context)),
+            posted: Arc::default(),
+            queue_policy: QueuePolicy::default(),
+            no_transition_policy: NoTransitionPolicy::default(),
+            resources: Arc::default(),
+            clock: Duration::ZERO,
+            timers: Vec::new(),
+        }
+    }
+
+    /// new_at is like new, but fails with FSMError::InvalidEntryPoint unless
+    /// entry is one of entry_points, e.g. Draft for new orders and Imported
+    /// for migrated ones. The context starts as C::default().
+    pub fn new_at<E>(
+        entry: S,
+        entry_points: impl IntoIterator<Item = S>,
+        events: impl IntoIterator<Item = E>,
+        hooks: impl IntoIterator<Item = (HookType<E::Name, S>, F)>,
+    ) -> Result<Self, FSMError<String>>
+    where
+        E: IntoEventDesc<S>,
+        C: Default,
+    {
+        if !entry_points.into_iter().any(|state| state == entry) {
+            return Err(FSMError::InvalidEntryPoint(entry.to_string()));
         }
+        Ok(Self::new_with_context(entry, events, hooks, C::default()))
     }
 
     /// get_current returns the current state of the FSM.
@@ -179,391 +810,4304 @@ where
         self.current.clone()
     }
 
-    /// on_event initiates a state transition with the named event.
-    //
-    // The call takes a variable number of arguments that will be passed to the
-    // callback, if defined.
-    pub fn on_event<T: AsRef<str>>(
-        &mut self,
-        event: T,
-        args: Option<&I>,
-    ) -> Result<(), FSMError<String>> {
-        let dst = self
-            .transitions
-            .get(&EKey {
-                event: Cow::Borrowed(event.as_ref()),
-                src: Cow::Owned(self.current.to_string()),
-            })
-            .ok_or_else(|| {
-                let e = event.as_ref().to_string();
-                for ekey in self.transitions.keys() {
-                    if ekey.event.eq(&e) {
-                        return FSMError::InvalidEvent(e, self.current.to_string());
-                    }
-                }
-                FSMError::UnknownEvent(e)
-            })?;
+    /// set_state forces the FSM into state without running any callbacks,
+    /// e.g. to rehydrate a persisted machine. A deferred transition is
+    /// dropped.
+    pub fn set_state(&mut self, state: S) {
+        self.pending = None;
+        self.previous = None;
+        self.current = state;
+        self.release_resources();
+    }
 
-        let e = Event {
-            event: event.as_ref(),
-            src: &self.current.clone(),
-            dst,
-            args,
-        };
+    /// start runs the EnterState callbacks of the current state, which is
+    /// otherwise never entered, so setup logic for the initial state can live
+    /// in its hooks. Failures are handled by the hook policy.
+    //
+    // Callbacks receive an Event with an empty event name whose source and
+    // destination are the current state. The EnterState callbacks of its
+    // ancestors run first.
+    pub fn start(&mut self) -> Result<(), FSMError<String>> {
+        let current = self.current.clone();
+        let metadata = self.metadata.clone();
+        let posted = self.posted.clone();
+        let resources = self.resources.clone();
+        let context = self.context.clone();
+        let e = Event::new(
+            "", &current, &current, None, &metadata, &posted, &resources, &context,
+        );
+        let hooks = self.hook_set("", None, current.as_ref());
+        if self.buffer(StepKind::Started, &e, &hooks.enter_state, &[]) {
+            return Ok(());
+        }
+        let mut trace = Vec::new();
+        if let Err(err) =
+            self.run_hooks(CallbackType::EnterState, &hooks.enter_state, &e, &mut trace)
+        {
+            self.hook_failed(
+                CallbackType::EnterState,
+                &e,
+                FSMError::InternalError(err.to_string()),
+                &trace,
+            )?;
+        }
+        Ok(())
+    }
 
-        self.before_event_callbacks(&e)
-            .map_err(|err| FSMError::InternalError(err.to_string()))?;
+    /// set_state_with_hooks forces the FSM into state like set_state, but
+    /// runs the LeaveState callbacks of the current state and the EnterState
+    /// callbacks of the new one. Failures are handled by the hook policy.
+    //
+    // Callbacks receive an Event with an empty event name. Nothing runs if
+    // state is already the current state.
+    pub fn set_state_with_hooks(&mut self, state: S) -> Result<(), FSMError<String>> {
+        self.pending = None;
+        self.previous = None;
+        if self.current.eq(&state) {
+            return Ok(());
+        }
 
-        if self.current.eq(dst) {
-            if let Err(err) = self.after_event_callbacks(&e) {
-                return Err(FSMError::NoTransitionWithError(err.to_string()));
+        let src = self.current.clone();
+        let metadata = self.metadata.clone();
+        let posted = self.posted.clone();
+        let resources = self.resources.clone();
+        let context = self.context.clone();
+        let e = Event::new(
+            "", &src, &state, None, &metadata, &posted, &resources, &context,
+        );
+        let hooks = self.hook_set("", Some(src.as_ref()), state.as_ref());
+        let mut trace = Vec::new();
+        if let Err(err) =
+            self.run_hooks(CallbackType::LeaveState, &hooks.leave_state, &e, &mut trace)
+        {
+            self.hook_failed(
+                CallbackType::LeaveState,
+                &e,
+                FSMError::InternalError(err.to_string()),
+                &trace,
+            )?;
+        }
+        self.current = state.clone();
+        if self.buffer(StepKind::Entered, &e, &hooks.enter_state, &[]) {
+            return Ok(());
+        }
+        let left = self.detach_left(&src, &state);
+        if let Err(err) =
+            self.run_hooks(CallbackType::EnterState, &hooks.enter_state, &e, &mut trace)
+        {
+            let err = FSMError::InternalError(err.to_string());
+            let ret = self.hook_failed(CallbackType::EnterState, &e, err, &trace);
+            if ret.is_err()
+                && self.hook_policy.get(&CallbackType::EnterState) == FailurePolicy::Rollback
+            {
+                self.resources.reattach(left);
             }
-            return Err(FSMError::NoTransition);
+            ret?;
         }
-
-        self.leave_state_callbacks(&e)
-            .map_err(|err| FSMError::InternalError(err.to_string()))?;
-        self.current = dst.clone();
-
-        // ignore errors
-        let _ = self.enter_state_callbacks(&e);
-        let _ = self.after_event_callbacks(&e);
-
         Ok(())
     }
 
-    /// is returns true if state is the current state.
-    pub fn is<T: AsRef<S>>(&self, state: T) -> bool {
-        self.current.eq(state.as_ref())
+    /// rollback reverts the last transition, e.g. to compensate for a
+    /// downstream failure after it committed: the FSM moves back to the
+    /// source state of that transition like set_state_with_hooks, running
+    /// the LeaveState and EnterState callbacks.
+    //
+    // Only one transition can be reverted; rollback fails with
+    // FSMError::NoTransition if there is none, including after set_state or
+    // another rollback.
+    pub fn rollback(&mut self) -> Result<(), FSMError<String>> {
+        let previous = self.previous.take().ok_or(FSMError::NoTransition)?;
+        self.set_state_with_hooks(previous)
     }
 
-    /// can returns true if event can occur in the current state.
-    pub fn can<T: AsRef<str>>(&self, event: T) -> bool {
-        self.transitions.contains_key(&EKey {
-            event: Cow::Borrowed(event.as_ref()),
-            src: Cow::Borrowed(self.current.as_ref()),
-        })
-    }
-}
+    /// transact applies the events sent by tx as one unit: if any of them
+    /// fails, the FSM is restored to its state before the transaction and the
+    /// error is returned.
+    //
+    // tx runs once, against the FSM itself. The EnterState and AfterEvent
+    // callbacks of the steps it takes are buffered and only run, in order,
+    // once tx succeeds; if one of them fails and the hook policy does not
+    // ignore it, the transaction is rolled back as well, and a Poison policy
+    // still poisons the FSM. Rolling back restores the whole runtime state:
+    // current state, history, entries, queued, posted and forced events,
+    // checkpoints, timers, metadata and context. Held resources are released
+    // on commit, not while tx runs, so the resources of the states left
+    // survive a rollback. A nested transact joins the transaction it runs
+    // in, and only rolls back its own steps.
+    pub fn transact<R, G>(&mut self, tx: G) -> Result<R, FSMError<String>>
+    where
+        G: FnOnce(&mut Self) -> Result<R, FSMError<String>>,
+        I: Clone,
+        C: Clone,
+    {
+        let snapshot = self.clone();
+        // mark is the number of steps buffered by the enclosing transaction
+        let mark = self.transaction.as_ref().map(|t| t.steps.len());
+        if mark.is_none() {
+            self.transaction = Some(Transaction {
+                clone: I::clone,
+                steps: Vec::new(),
+            });
+        }
+        let ret = tx(self);
+        let mut transaction = self.transaction.take();
+        if let Some(mark) = mark {
+            if ret.is_err() {
+                self.restore(snapshot);
+                if let Some(t) = transaction.as_mut() {
+                    t.steps.truncate(mark);
+                }
+            }
+            self.transaction = transaction;
+            return ret;
+        }
+        if ret.is_err() {
+            self.restore(snapshot);
+            self.release_resources();
+            return ret;
+        }
 
-impl<'a, S, I, F> FSM<'a, S, I, F>
-where
-    S: FSMState,
-    I: IntoIterator,
-    F: Action<S, I>,
-{
-    #[inline]
-    fn before_event_callbacks(&self, e: &Event<S, I>) -> Result<(), F::Err> {
-        if let Some(f) = self.callbacks.get(&CKey {
-            target: Cow::Borrowed(e.event),
-            callback_type: CallbackType::BeforeEvent,
-        }) {
-            f.call(e)?;
-        }
-        if let Some(f) = self.callbacks.get(&CKey {
-            target: Cow::Borrowed(""),
-            callback_type: CallbackType::BeforeEvent,
-        }) {
-            f.call(e)?;
+        let steps = transaction.map(|t| t.steps).unwrap_or_default();
+        if let Err(err) = self.commit(steps) {
+            let poisoned = self.poisoned;
+            self.restore(snapshot);
+            self.poisoned |= poisoned;
+            self.release_resources();
+            return Err(err);
         }
-        Ok(())
+        ret
     }
 
-    #[inline]
-    fn after_event_callbacks(&self, e: &Event<S, I>) -> Result<(), F::Err> {
-        if let Some(f) = self.callbacks.get(&CKey {
-            target: Cow::Borrowed(e.event),
-            callback_type: CallbackType::AfterEvent,
-        }) {
-            f.call(e)?;
-        }
-        if let Some(f) = self.callbacks.get(&CKey {
-            target: Cow::Borrowed(""),
-            callback_type: CallbackType::AfterEvent,
-        }) {
-            f.call(e)?;
-        }
-        Ok(())
+    /// on_event initiates a state transition with the named event, and
+    /// returns the transition that took place.
+    //
+    // The call takes a variable number of arguments that will be passed to the
+    // callback, if defined. The event can be a name, raw bytes wrapped in
+    // Bytes, or either of them stamped with a sequence number by Sequenced,
+    // see AsEvent. De-duplication, deferral and args summaries apply to every
+    // event once configured.
+    pub fn on_event<E: AsEvent>(
+        &mut self,
+        event: E,
+        args: Option<&I>,
+    ) -> Result<EventRecord<S>, FSMError<String>> {
+        self.fire(event.name()?, args, event.sequence())
     }
 
-    #[inline]
-    fn enter_state_callbacks(&self, e: &Event<S, I>) -> Result<(), F::Err> {
-        if let Some(f) = self.callbacks.get(&CKey {
-            target: Cow::Borrowed(self.current.as_ref()),
-            callback_type: CallbackType::EnterState,
-        }) {
-            f.call(e)?;
-        }
-        if let Some(f) = self.callbacks.get(&CKey {
-            target: Cow::Borrowed(""),
-            callback_type: CallbackType::EnterState,
-        }) {
-            f.call(e)?;
+    // fire checks event against the last applied sequence and the dedup
+    // window, runs its transition like apply, reports the errors meant for
+    // the OnError callback and records the outcome in the history. It then
+    // replays the queued events the new state accepts.
+    pub(crate) fn fire(
+        &mut self,
+        event: &str,
+        args: Option<&I>,
+        sequence: Option<u64>,
+    ) -> Result<EventRecord<S>, FSMError<String>> {
+        let src = (self.history_capacity > 0).then(|| self.current.clone());
+        let mut ret = self
+            .admit(event, args, sequence)
+            .and_then(|()| self.apply(event, args, sequence));
+        if let Err(err) = ret {
+            ret = Err(self.summarized(err, args));
         }
-        Ok(())
+        match &ret {
+            Ok(_) => {
+                if sequence.is_some() {
+                    self.sequence = sequence;
+                }
+            }
+            Err(err) => {
+                if err.kind() == ErrorKind::Hook
+                    || matches!(err.code(), "unknown_event" | "invalid_event")
+                {
+                    self.report_error(event, args, err);
+                }
+            }
+        }
+        if let Some(src) = src {
+            match &ret {
+                Ok(record) => self.push_history(record.clone(), None),
+                Err(err) => {
+                    let record = EventRecord {
+                        event: event.to_string(),
+                        src,
+                        dst: self.current.clone(),
+                    };
+                    self.push_history(record, Some(err.to_string()));
+                }
+            }
+        }
+        if ret.is_ok() {
+            self.replay_queued();
+        }
+        ret
     }
 
-    #[inline]
-    fn leave_state_callbacks(&self, e: &Event<S, I>) -> Result<(), F::Err> {
-        if let Some(f) = self.callbacks.get(&CKey {
-            target: Cow::Borrowed(self.current.as_ref()),
-            callback_type: CallbackType::LeaveState,
-        }) {
-            f.call(e)?;
-        }
-        if let Some(f) = self.callbacks.get(&CKey {
-            target: Cow::Borrowed(""),
-            callback_type: CallbackType::LeaveState,
-        }) {
-            f.call(e)?;
+    // admit rejects a sequenced event that is not newer than the last applied
+    // one, and an event seen within the dedup window.
+    fn admit(
+        &mut self,
+        event: &str,
+        args: Option<&I>,
+        sequence: Option<u64>,
+    ) -> Result<(), FSMError<String>> {
+        if let (Some(seq), Some(last)) = (sequence, self.sequence) {
+            if seq <= last {
+                return Err(FSMError::StaleEvent(seq, last));
+            }
+        }
+        if let Some(dedup) = self.dedup.as_mut() {
+            if dedup.check(event, args, Instant::now()) {
+                return Err(FSMError::DuplicateEvent(event.to_string()));
+            }
         }
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{EventDesc, FSMState, HookType, FSM};
-    use crate::{action::Closure, error::FSMError, event::Event, Action};
-    use std::{
-        collections::HashMap,
-        fmt::{Debug, Display},
-        sync::{
-            atomic::{AtomicU32, Ordering},
-            Arc, Mutex,
-        },
-        thread,
-    };
-    use strum::AsRefStr;
-    use strum::Display;
-    use thiserror::Error;
+    // apply runs the transition for event, stamping the Event passed to the
+    // callbacks with the source sequence number, if any.
+    fn apply(
+        &mut self,
+        event: &str,
+        args: Option<&I>,
+        sequence: Option<u64>,
+    ) -> Result<EventRecord<S>, FSMError<String>> {
+        if self.poisoned {
+            return Err(FSMError::Poisoned);
+        }
+        if self.is_finished() {
+            return Err(FSMError::MachineFinished(self.current.to_string()));
+        }
+        if self.pending.is_some() {
+            return Err(FSMError::InTransition(event.to_string()));
+        }
 
-    #[derive(Debug, Error)]
-    enum MyError {
-        #[error("my error: {0}")]
-        CustomeError(&'static str),
+        let current = self.current.to_string();
+        let (declared, dst) = match self.lookup(event, &current) {
+            Some((declared, dst)) => (declared.to_string(), dst.clone()),
+            None if self.is_deferrable(event) => return self.queue(event, args),
+            None => return self.fall_back(event, args, sequence),
+        };
+
+        let transformed = match (args, self.transformers.get(event)) {
+            (Some(args), Some(transform)) => Some(transform(args)),
+            _ => None,
+        };
+        let args = transformed.as_ref().or(args);
+        let dst = match self.choosers.get(event) {
+            Some(choose) => choose(&self.current, args),
+            None => dst,
+        };
+
+        let src = self.current.clone();
+        let hooks = self.cached_hooks(src.as_ref(), event, &declared, dst.as_ref());
+        let metadata = self.metadata.clone();
+        let posted = self.posted.clone();
+        let resources = self.resources.clone();
+        let context = self.context.clone();
+        let e = Event::new(
+            event, &src, &dst, args, &metadata, &posted, &resources, &context,
+        );
+        e.set_sequence(sequence);
+
+        // trace records the hooks that already succeeded, so a failing hook
+        // can report which side effects have happened before it.
+        let mut trace = Vec::new();
+
+        let ret = self.run_hooks(
+            CallbackType::BeforeEvent,
+            &hooks.before_event,
+            &e,
+            &mut trace,
+        );
+        if let Some(reason) = e.take_cancel_reason() {
+            return Err(FSMError::Canceled(reason));
+        }
+        if let Err(err) = ret {
+            self.hook_failed(
+                CallbackType::BeforeEvent,
+                &e,
+                FSMError::InternalError(err.to_string()),
+                &trace,
+            )?;
+        }
+
+        let policy = if self.externals.contains(event) {
+            NoTransitionPolicy::Reenter
+        } else {
+            self.no_transition_policy
+        };
+        if self.current.eq(&dst) && policy != NoTransitionPolicy::Reenter {
+            if self.buffer(StepKind::Stayed, &e, &[], &hooks.after_event) {
+                // the AfterEvent callbacks run on commit
+            } else if let Err(err) =
+                self.run_hooks(CallbackType::AfterEvent, &hooks.after_event, &e, &mut trace)
+            {
+                self.poison_if_dirty(&e);
+                return Err(with_trace(
+                    FSMError::NoTransitionWithError(err.to_string()),
+                    trace,
+                ));
+            }
+            return match policy {
+                NoTransitionPolicy::Ignore => Ok(e.to_record()),
+                _ => Err(FSMError::NoTransition),
+            };
+        }
+
+        let ret = self.run_hooks(CallbackType::LeaveState, &hooks.leave_state, &e, &mut trace);
+        if let Some(reason) = e.take_cancel_reason() {
+            return Err(FSMError::Canceled(reason));
+        }
+        if let Err(err) = ret {
+            self.hook_failed(
+                CallbackType::LeaveState,
+                &e,
+                FSMError::InternalError(err.to_string()),
+                &trace,
+            )?;
+        }
+        if e.is_deferred() {
+            self.pending = Some(Pending {
+                event: event.to_string(),
+                src,
+                dst,
+                declared,
+            });
+            return Err(FSMError::Deferred(event.to_string()));
+        }
+
+        self.enter(&e, &hooks, &mut trace)?;
+        Ok(e.to_record())
     }
 
-    #[derive(Display, AsRefStr, Debug, Clone, Hash, PartialEq, Eq)]
-    enum StateTag {
-        #[strum(serialize = "opened")]
-        Opened,
-        #[strum(serialize = "closed")]
-        Closed,
+    /// force_event moves the FSM to dst with event, overriding the usual
+    /// checks, e.g. to unstick a workflow by hand. dst must be declared as a
+    /// destination of event, from any source state; otherwise it fails with
+    /// FSMError::InvalidEvent.
+    //
+    // The BeforeEvent callbacks do not run, and the LeaveState callbacks
+    // cannot cancel, defer or fail the transition. The transition runs even
+    // if the FSM is poisoned, finished or in a deferred transition, which is
+    // dropped. Forced transitions are recorded before their callbacks run,
+    // so get_forced lists them even if a callback then fails.
+    pub fn force_event<T: AsRef<str>>(
+        &mut self,
+        event: T,
+        dst: S,
+        args: Option<&I>,
+    ) -> Result<EventRecord<S>, FSMError<String>> {
+        let event = event.as_ref();
+        // the transition that would match from the current state, if it leads
+        // to dst, or else any transition of event to dst
+        let declared = match self.lookup(event, self.current.as_ref()) {
+            Some((declared, to)) if *to == dst => Some(declared),
+            _ => self
+                .transitions
+                .iter()
+                .find(|(k, to)| k.event == event && **to == dst)
+                .map(|(k, _)| k.src.as_ref()),
+        };
+        let Some(declared) = declared.map(str::to_string) else {
+            return Err(FSMError::InvalidEvent(
+                event.to_string(),
+                self.current.to_string(),
+            ));
+        };
+        self.pending = None;
+
+        let src = self.current.clone();
+        let hooks = self.cached_hooks(src.as_ref(), event, &declared, dst.as_ref());
+        let metadata = self.metadata.clone();
+        let posted = self.posted.clone();
+        let resources = self.resources.clone();
+        let context = self.context.clone();
+        let e = Event::new(
+            event, &src, &dst, args, &metadata, &posted, &resources, &context,
+        );
+        let record = e.to_record();
+        if self.forced_capacity > 0 {
+            if self.forced.len() == self.forced_capacity {
+                self.forced.pop_front();
+            }
+            self.forced.push_back(record.clone());
+        }
+
+        let mut trace = Vec::new();
+        let _ = self.run_hooks(CallbackType::LeaveState, &hooks.leave_state, &e, &mut trace);
+        if let Err(err) = self.enter(&e, &hooks, &mut trace) {
+            let failed = EventRecord {
+                dst: self.current.clone(),
+                ..record
+            };
+            self.push_history(failed, Some(err.to_string()));
+            return Err(err);
+        }
+        self.push_history(record.clone(), None);
+        self.replay_queued();
+        Ok(record)
     }
-    impl FSMState for StateTag {}
-    impl AsRef<Self> for StateTag {
-        fn as_ref(&self) -> &Self {
-            &self
+
+    /// get_forced iterates over the last transitions performed by
+    /// force_event, oldest first.
+    pub fn get_forced(&self) -> impl Iterator<Item = &EventRecord<S>> {
+        self.forced.iter()
+    }
+
+    /// set_forced_capacity sets how many of the last forced transitions are
+    /// kept, dropping the oldest ones if needed. It defaults to 16.
+    pub fn set_forced_capacity(&mut self, capacity: usize) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        self.forced_capacity = capacity;
+        while self.forced.len() > capacity {
+            self.forced.pop_front();
         }
+        Ok(())
     }
 
-    #[derive(Display, AsRefStr, Debug, Clone, Hash, PartialEq, Eq)]
-    enum EventTag {
-        #[strum(serialize = "open")]
-        Open,
-        #[strum(serialize = "close")]
-        Close,
+    /// transition completes a transition deferred by Event::defer: the FSM
+    /// enters the destination state and runs the EnterState and AfterEvent
+    /// callbacks.
+    //
+    // The args given to the deferring on_event call are not kept, so these
+    // callbacks see no args.
+    pub fn transition(&mut self) -> Result<EventRecord<S>, FSMError<String>> {
+        let pending = self.pending.take().ok_or(FSMError::NotInTransition)?;
+        let hooks = self.cached_hooks(
+            pending.src.as_ref(),
+            &pending.event,
+            &pending.declared,
+            pending.dst.as_ref(),
+        );
+        let metadata = self.metadata.clone();
+        let posted = self.posted.clone();
+        let resources = self.resources.clone();
+        let context = self.context.clone();
+        let e = Event::new(
+            &pending.event,
+            &pending.src,
+            &pending.dst,
+            None,
+            &metadata,
+            &posted,
+            &resources,
+            &context,
+        );
+        self.enter(&e, &hooks, &mut Vec::new())?;
+        let record = e.to_record();
+        self.push_history(record.clone(), None);
+        self.replay_queued();
+        Ok(record)
     }
 
-    type FSMWithHashMap<'a> =
-        FSM<'a, StateTag, HashMap<u32, u32>, Closure<'a, StateTag, HashMap<u32, u32>, MyError>>;
-    type FSMWithVec<'a> = FSM<'a, StateTag, Vec<u32>, Closure<'a, StateTag, Vec<u32>, MyError>>;
+    /// set_context replaces the context of the FSM, see new_with_context.
+    //
+    // A clone of the FSM gets its own copy of the context.
+    pub fn set_context(&mut self, context: C) {
+        *self.get_context() = context;
+    }
 
-    #[test]
-    fn test_fsm_state() {
-        {
-            let mut fsm: FSMWithHashMap = FSM::new(
-                StateTag::Closed,
-                vec![
-                    EventDesc {
-                        name: EventTag::Open,
-                        src: vec![StateTag::Closed],
-                        dst: StateTag::Opened,
-                    },
-                    EventDesc {
-                        name: EventTag::Close,
-                        src: vec![StateTag::Opened],
-                        dst: StateTag::Closed,
-                    },
-                ],
-                HashMap::new(),
-            );
-            assert_eq!(StateTag::Closed, fsm.get_current());
-            assert!(fsm.is(StateTag::Closed));
-            assert!(fsm.is(&StateTag::Closed));
+    /// get_context locks and returns the context of the FSM.
+    pub fn get_context(&self) -> MutexGuard<'_, C> {
+        lock(&self.context)
+    }
 
-            assert!(fsm.can(EventTag::Open));
-            assert!(fsm.on_event("open", Some(&HashMap::new())).is_ok());
-            assert_eq!(StateTag::Opened, fsm.get_current());
-            assert!(fsm.is(StateTag::Opened));
-            assert!(fsm.is(&StateTag::Opened));
+    /// post appends event to the queue of posted events, to be processed by
+    /// dispatch. Callbacks can post follow-up events with Event::post.
+    pub fn post<T: AsRef<str>>(&mut self, event: T, args: Option<I>) {
+        self.post_with_priority(event, 0, args);
+    }
 
-            assert!(fsm.can(EventTag::Close));
-            assert!(fsm.on_event("close", Some(&HashMap::new())).is_ok());
-            assert_eq!(StateTag::Closed, fsm.get_current());
-            assert!(fsm.is(StateTag::Closed));
-            assert!(fsm.is(&StateTag::Closed));
+    /// post_with_priority is like post, but gives the event a priority:
+    /// unless the queue policy is QueuePolicy::Fifo, events with a higher
+    /// priority, e.g. an abort, are dispatched before the ones already
+    /// queued. post uses priority 0.
+    pub fn post_with_priority<T: AsRef<str>>(&mut self, event: T, priority: i32, args: Option<I>) {
+        self.posted.push(event.as_ref().to_string(), args, priority);
+    }
 
-            assert!(!fsm.can(EventTag::Close));
-            let ret = fsm.on_event("close", None);
-            assert!(ret.is_err());
-            assert_eq!(
-                ret.err().unwrap(),
-                FSMError::InvalidEvent("close".to_string(), StateTag::Closed.to_string())
-            );
-            assert_eq!(StateTag::Closed, fsm.get_current());
-            assert!(fsm.is(StateTag::Closed));
+    /// set_queue_policy sets the order in which posted events are
+    /// dispatched. It defaults to QueuePolicy::Priority.
+    pub fn set_queue_policy(&mut self, policy: QueuePolicy) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        self.queue_policy = policy;
+        Ok(())
+    }
+
+    /// dispatch processes the posted events one at a time, in the order set
+    /// by the queue policy, including the ones posted by callbacks meanwhile,
+    /// until none is left. It returns how many events were processed.
+    //
+    // Each event runs to completion, callbacks included, before the next one
+    // starts. The first failing event stops the dispatch and its error is
+    // returned; it is put back at the head of the queue with the events after
+    // it, so it is retried by the next dispatch unless skip_posted drops it.
+    // An event that is deferred or queued has been accepted, so it counts as
+    // processed and is not put back.
+    pub fn dispatch(&mut self) -> Result<usize, FSMError<String>> {
+        let mut count = 0;
+        while let Some(q) = self.posted.pop(self.queue_policy) {
+            match self.on_event(&q.event, q.args.as_deref()) {
+                Ok(_) | Err(FSMError::Deferred(_) | FSMError::Queued(_)) => count += 1,
+                Err(err) => {
+                    self.posted.push_front(q);
+                    return Err(err);
+                }
+            }
         }
+        Ok(count)
+    }
 
-        {
-            let mut fsm: FSMWithVec = FSM::new(
-                StateTag::Closed,
-                vec![
-                    EventDesc {
-                        name: EventTag::Open,
-                        src: vec![StateTag::Closed],
-                        dst: StateTag::Opened,
-                    },
-                    EventDesc {
-                        name: EventTag::Close,
-                        src: vec![StateTag::Opened],
-                        dst: StateTag::Closed,
-                    },
-                ],
-                HashMap::new(),
-            );
-            assert_eq!(StateTag::Closed, fsm.get_current());
+    /// skip_posted drops the next posted event without dispatching it, e.g.
+    /// after dispatch failed on it, and returns its name.
+    pub fn skip_posted(&mut self) -> Option<String> {
+        self.posted.pop(self.queue_policy).map(|q| q.event)
+    }
+
+    /// fire_after schedules event to fire once delay has elapsed on the clock
+    /// driven by tick, e.g. for retries and timeouts.
+    //
+    // Timers due at the same time fire in the order they were scheduled.
+    pub fn fire_after<T: AsRef<str>>(&mut self, event: T, delay: Duration, args: Option<I>) {
+        let due = self.clock + delay;
+        let i = self.timers.partition_point(|timer| timer.due <= due);
+        self.timers.insert(
+            i,
+            Timer {
+                due,
+                queued: Queued {
+                    event: event.as_ref().to_string(),
+                    args: args.map(Arc::new),
+                    priority: 0,
+                },
+            },
+        );
+    }
+
+    /// cancel_timers removes the scheduled timers of event, and returns how
+    /// many were removed.
+    pub fn cancel_timers<T: AsRef<str>>(&mut self, event: T) -> usize {
+        let count = self.timers.len();
+        self.timers
+            .retain(|timer| timer.queued.event != event.as_ref());
+        count - self.timers.len()
+    }
+
+    /// tick advances the clock by elapsed and fires the timers that became
+    /// due, oldest first. It returns how many timers fired.
+    //
+    // The clock is driven by the caller only, so time can be simulated in
+    // tests. Every due timer fires, even after one failed; the first error is
+    // then returned. A timer whose event failed stays scheduled and is fired
+    // again by the next tick, until it succeeds or cancel_timers removes it.
+    // An event that is deferred or queued has been accepted, so its timer
+    // counts as fired.
+    pub fn tick(&mut self, elapsed: Duration) -> Result<usize, FSMError<String>> {
+        self.clock += elapsed;
+        let clock = self.clock;
+        let due = self.timers.partition_point(|timer| timer.due <= clock);
+        let timers: Vec<_> = self.timers.drain(..due).collect();
+        let mut count = 0;
+        let mut failed = Vec::new();
+        let mut first = None;
+        for timer in timers {
+            match self.on_event(&timer.queued.event, timer.queued.args.as_deref()) {
+                Ok(_) | Err(FSMError::Deferred(_) | FSMError::Queued(_)) => count += 1,
+                Err(err) => {
+                    first.get_or_insert(err);
+                    failed.push(timer);
+                }
+            }
+        }
+        // failed timers are due already, so they stay ahead of the others
+        self.timers.splice(0..0, failed);
+        match first {
+            Some(err) => Err(err),
+            None => Ok(count),
+        }
+    }
+
+    /// get_resource_count returns how many resources callbacks hold for the
+    /// current state and its ancestors, see Event::hold.
+    //
+    // Resources are not cloned: a clone of the FSM starts without any.
+    pub fn get_resource_count(&self) -> usize {
+        self.resources.len()
+    }
+
+    /// get_posted_count returns how many posted events are waiting for
+    /// dispatch.
+    pub fn get_posted_count(&self) -> usize {
+        self.posted.len()
+    }
+
+    /// is_in_transition returns true if a deferred transition is waiting for
+    /// transition to be called.
+    pub fn is_in_transition(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// set_metadata stores value under key, replacing any previous value.
+    /// Callbacks can read and change metadata through the Event.
+    //
+    // Metadata belongs to the FSM instance: a clone starts with a copy of it.
+    pub fn set_metadata<K: Into<String>, T: Any + Send + Sync>(&mut self, key: K, value: T) {
+        self.metadata.set(key.into(), value);
+    }
+
+    /// get_metadata returns the metadata value stored under key, if any and
+    /// if it is a T.
+    pub fn get_metadata<T: Any + Send + Sync>(&self, key: &str) -> Option<Arc<T>> {
+        self.metadata.get(key)
+    }
+
+    /// delete_metadata removes the metadata value stored under key, and
+    /// returns true if there was one.
+    pub fn delete_metadata(&mut self, key: &str) -> bool {
+        self.metadata.delete(key)
+    }
+
+    /// freeze_hooks freezes the configuration of the FSM, e.g. before handing
+    /// it to plugins: every setter that changes how events are handled, from
+    /// set_chooser and set_hook_policy to set_history_capacity, then fails
+    /// with FSMError::Frozen and changes nothing, as does prune_unreachable.
+    /// There is no way to thaw a frozen FSM, and its clones are frozen too.
+    //
+    // The callback table is fixed by new and cannot be changed at all. The
+    // metadata, the context and the runtime state, e.g. set_state, are not
+    // part of the configuration.
+    pub fn freeze_hooks(&mut self) {
+        self.frozen = true;
+    }
+
+    /// is_frozen returns true if freeze_hooks was called.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// set_transformer registers a function that rewrites the args of event
+    /// before any callback sees them, e.g. to parse or enrich them once.
+    //
+    // The transformer only runs when args are given, and replaces any
+    // transformer previously registered for the event.
+    pub fn set_transformer<T, G>(&mut self, event: T, transform: G) -> Result<(), FSMError<String>>
+    where
+        T: AsRef<str>,
+        G: Fn(&I) -> I + Send + Sync + 'a,
+    {
+        self.check_frozen()?;
+        Arc::make_mut(&mut self.transformers)
+            .insert(event.as_ref().to_string(), Arc::new(transform));
+        Ok(())
+    }
+
+    /// set_chooser registers a function that picks the destination of event
+    /// at dispatch time from the current state and the args, so one event
+    /// can branch to different states.
+    //
+    // The event must still be declared, and only fires from its declared
+    // source states; the chooser replaces the declared destination. It sees
+    // the args after any transformer ran.
+    pub fn set_chooser<T, G>(&mut self, event: T, choose: G) -> Result<(), FSMError<String>>
+    where
+        T: AsRef<str>,
+        G: Fn(&S, Option<&I>) -> S + Send + Sync + 'a,
+    {
+        self.check_frozen()?;
+        Arc::make_mut(&mut self.choosers).insert(event.as_ref().to_string(), Arc::new(choose));
+        Ok(())
+    }
+
+    /// set_fallback sets the handler of the events that have no transition
+    /// from the current state, which can ignore the event, translate it into
+    /// another one, or reject it as on_event would without a handler.
+    //
+    // An ignored event returns Ok with the current state as both src and
+    // dst, and runs no callbacks. A translated event is fired with the same
+    // args, without going through the handler again.
+    pub fn set_fallback<G>(&mut self, fallback: G) -> Result<(), FSMError<String>>
+    where
+        G: Fn(&S, &str) -> Fallback + Send + Sync + 'a,
+    {
+        self.check_frozen()?;
+        self.fallback = Some(Arc::new(fallback));
+        Ok(())
+    }
+
+    /// set_parent nests state inside parent. Transitions declared on parent
+    /// then also fire from state, and the LeaveState and EnterState
+    /// callbacks of the ancestors run when a transition leaves or enters
+    /// them. It fails with FSMError::InvalidParent, leaving the hierarchy
+    /// unchanged, if parent is state or one of its descendants.
+    //
+    // Transitions declared on state take precedence over those of its
+    // ancestors. A state has at most one parent; setting another replaces it.
+    pub fn set_parent(&mut self, state: S, parent: S) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        let state: &str = state.as_ref();
+        let parent: &str = parent.as_ref();
+        if self.ancestors(parent).any(|s| s == state) {
+            return Err(FSMError::InvalidParent(
+                state.to_string(),
+                parent.to_string(),
+            ));
+        }
+        Arc::make_mut(&mut self.parents).insert(state.to_string(), parent.to_string());
+        self.hook_cache.entries = Arc::default();
+        Ok(())
+    }
+
+    /// set_group tags state with group, so the EnterGroup and LeaveGroup
+    /// hooks of group run when the FSM enters or leaves it. A state belongs
+    /// to the groups of its ancestors too.
+    pub fn set_group<T: AsRef<str>>(&mut self, state: S, group: T) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        let state: &str = state.as_ref();
+        Arc::make_mut(&mut self.groups)
+            .entry(state.to_string())
+            .or_default()
+            .insert(group.as_ref().to_string());
+        self.hook_cache.entries = Arc::default();
+        Ok(())
+    }
+
+    /// in_group returns true if the current state belongs to group.
+    pub fn in_group<T: AsRef<str>>(&self, group: T) -> bool {
+        self.ancestors(self.current.as_ref())
+            .filter_map(|state| self.groups.get(state))
+            .any(|groups| groups.contains(group.as_ref()))
+    }
+
+    /// set_final marks state as final: once the FSM reaches it, it is
+    /// finished and rejects every event with FSMError::MachineFinished.
+    //
+    // set_state can still move a finished FSM, e.g. to recover it.
+    pub fn set_final(&mut self, state: S) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        let state: &str = state.as_ref();
+        Arc::make_mut(&mut self.finals).insert(state.to_string());
+        Ok(())
+    }
+
+    /// is_finished returns true if the current state is a final state.
+    pub fn is_finished(&self) -> bool {
+        let current: &str = self.current.as_ref();
+        self.finals.contains(current)
+    }
+
+    /// set_no_transition_policy sets what an event leaving the FSM in the
+    /// same state does. It defaults to NoTransitionPolicy::Error.
+    //
+    // Events marked with set_external always re-enter the state.
+    pub fn set_no_transition_policy(
+        &mut self,
+        policy: NoTransitionPolicy,
+    ) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        self.no_transition_policy = policy;
+        Ok(())
+    }
+
+    /// set_external makes the self-transitions of event external: instead of
+    /// failing with FSMError::NoTransition, they leave and re-enter the
+    /// state, running the LeaveState and EnterState callbacks like any other
+    /// transition.
+    //
+    // on_event then returns Ok for such a self-transition, and the resources
+    // held for the state are dropped when it is left.
+    pub fn set_external<T: AsRef<str>>(&mut self, event: T) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        Arc::make_mut(&mut self.externals).insert(event.as_ref().to_string());
+        Ok(())
+    }
+
+    /// set_deferrable makes event deferrable in state and its descendants:
+    /// instead of being rejected there, on_event queues it and fails with
+    /// FSMError::Queued.
+    //
+    // Queued events are replayed, oldest first, after the next successful
+    // transition into a state that accepts them. A replayed event that fails
+    // is dropped.
+    pub fn set_deferrable<T: AsRef<str>>(
+        &mut self,
+        state: S,
+        event: T,
+    ) -> Result<(), FSMError<String>>
+    where
+        I: Clone,
+    {
+        self.check_frozen()?;
+        let state: &str = state.as_ref();
+        Arc::make_mut(&mut self.deferrable).insert(EKey {
+            event: Cow::Owned(event.as_ref().to_string()),
+            src: Cow::Owned(state.to_string()),
+        });
+        self.clone_args = Some(I::clone);
+        Ok(())
+    }
+
+    /// get_queued_count returns how many deferrable events are waiting to be
+    /// replayed.
+    pub fn get_queued_count(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// set_error_summary enables or disables attaching a summary of the args
+    /// to the errors of on_event, as FSMError::WithArgs, so support can see
+    /// what was attempted without the args themselves being logged. The
+    /// history records the summarized errors too.
+    pub fn set_error_summary(&mut self, enabled: bool) -> Result<(), FSMError<String>>
+    where
+        I: Summarize,
+    {
+        self.check_frozen()?;
+        self.summarize = Some(I::summarize);
+        self.summarize_errors = enabled;
+        Ok(())
+    }
+
+    /// set_entry_capture enables or disables keeping the last transition into
+    /// each state, with a summary of its args, see last_entry_info.
+    /// Disabling it drops what was kept.
+    //
+    // Summaries longer than 256 bytes are truncated.
+    pub fn set_entry_capture(&mut self, enabled: bool) -> Result<(), FSMError<String>>
+    where
+        I: Summarize,
+    {
+        self.check_frozen()?;
+        self.summarize = Some(I::summarize);
+        self.capture_entries = enabled;
+        if !enabled {
+            self.entries.clear();
+        }
+        Ok(())
+    }
+
+    /// last_entry_info returns the last transition into state and a summary
+    /// of its args, e.g. to see what drove the FSM into a failure state. It
+    /// returns None if entry capture is disabled or state was not entered
+    /// since it was enabled.
+    pub fn last_entry_info<T: AsRef<S>>(&self, state: T) -> Option<&EntryInfo<S>> {
+        let state: &str = state.as_ref().as_ref();
+        self.entries.get(state)
+    }
+
+    /// set_dedup_window enables de-duplication of events within window, or
+    /// disables it with None: on_event then rejects an event with
+    /// FSMError::DuplicateEvent if the same event with equal args was already
+    /// received within the window.
+    //
+    // The window is measured in real time, on a monotonic clock, so it
+    // elapses whether or not tick is called.
+    pub fn set_dedup_window(&mut self, window: Option<Duration>) -> Result<(), FSMError<String>>
+    where
+        I: Hash + Eq + Clone,
+    {
+        self.check_frozen()?;
+        self.dedup = window.map(Dedup::new);
+        Ok(())
+    }
+
+    /// get_suppressed_count returns how many events were suppressed as
+    /// duplicates since the dedup window was set.
+    pub fn get_suppressed_count(&self) -> u64 {
+        self.dedup.as_ref().map_or(0, Dedup::suppressed)
+    }
+
+    /// set_profiling enables or disables the recording of the wall time spent
+    /// in each hook, see get_profile. Disabling it drops what was recorded.
+    pub fn set_profiling(&mut self, enabled: bool) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        if !enabled {
+            self.profiler = None;
+        } else if self.profiler.is_none() {
+            self.profiler = Some(Profiler::default());
+        }
+        Ok(())
+    }
+
+    /// get_profile returns the time spent in each hook since profiling was
+    /// enabled, in folded-stack format ready for flamegraph tools: one
+    /// "event;hook microseconds" line per hook, e.g.
+    /// "open;before_event(open) 1250".
+    //
+    // Hooks run without an event, e.g. by start, are listed on their own.
+    pub fn get_profile(&self) -> String {
+        self.profiler
+            .as_ref()
+            .map_or_else(String::new, Profiler::folded)
+    }
+
+    /// hook_cache_stats returns the hit and miss counts of the cache of
+    /// resolved hooks per (state, event), and its number of entries.
+    //
+    // The cache is filled lazily by on_event; clones share the entries
+    // resolved so far but count hits and misses separately.
+    pub fn hook_cache_stats(&self) -> HookCacheStats {
+        HookCacheStats {
+            hits: self.hook_cache.hits,
+            misses: self.hook_cache.misses,
+            entries: self.hook_cache.entries.values().map(HashMap::len).sum(),
+        }
+    }
+
+    /// get_effects returns the side effects run by Event::once, as (sequence,
+    /// id) pairs in order, e.g. to persist them alongside the event log.
+    pub fn get_effects(&self) -> Vec<(u64, String)> {
+        self.metadata.effects()
+    }
+
+    /// set_effects replaces the side effects recorded by Event::once, e.g.
+    /// with the ones persisted by get_effects before rebuilding the FSM by
+    /// replaying its events.
+    pub fn set_effects<T: Into<String>>(&mut self, effects: impl IntoIterator<Item = (u64, T)>) {
+        self.metadata
+            .set_effects(effects.into_iter().map(|(seq, id)| (seq, id.into())));
+    }
+
+    /// ack_effects forgets the side effects recorded by Event::once for the
+    /// events with a sequence up to and including seq, once a snapshot of
+    /// the FSM at seq is persisted and replays start after it. Otherwise the
+    /// record grows with every sequenced event.
+    pub fn ack_effects(&mut self, seq: u64) {
+        self.metadata.prune_effects(seq);
+    }
+
+    /// get_sequence returns the sequence number of the last applied sequenced
+    /// event, if any.
+    pub fn get_sequence(&self) -> Option<u64> {
+        self.sequence
+    }
+
+    /// set_poison_mode enables or disables poisoning.
+    //
+    // When enabled, a callback failure on an event that was marked dirty via
+    // Event::mark_dirty poisons the FSM: every following event is rejected
+    // with FSMError::Poisoned until repair is called.
+    pub fn set_poison_mode(&mut self, enabled: bool) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        self.poison_on_dirty = enabled;
+        Ok(())
+    }
+
+    /// set_hook_policy sets how callback failures are handled, per callback
+    /// type.
+    //
+    // Failures of after_event callbacks on a self-transition are always
+    // reported as FSMError::NoTransitionWithError, since no transition
+    // happened.
+    pub fn set_hook_policy(&mut self, policy: HookPolicy) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        self.hook_policy = policy;
+        Ok(())
+    }
+
+    /// is_poisoned returns true if the FSM is poisoned.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// repair clears the poisoned flag, once the inconsistency has been
+    /// resolved outside the FSM.
+    pub fn repair(&mut self) {
+        self.poisoned = false;
+    }
+
+    /// missing_hooks returns the targets that have no hook of callback_type
+    /// registered for them, sorted by name.
+    //
+    // Targets are states for EnterState/LeaveState, events for
+    // BeforeEvent/AfterEvent and "event:src" pairs for Transition, with "*"
+    // as the src of the transitions declared from any state. Only
+    // targeted hooks count; a hook for every state or event, like
+    // HookType::EnterState, does not.
+    pub fn missing_hooks(&self, callback_type: CallbackType) -> Vec<String> {
+        let mut targets: Vec<Target> = match callback_type {
+            CallbackType::EnterState | CallbackType::LeaveState => self
+                .transitions
+                .iter()
+                .flat_map(|(k, dst)| [k.src.as_ref(), dst.as_ref()])
+                .filter(|state| !state.is_empty())
+                .map(|state| Target::Name(Cow::Borrowed(state)))
+                .collect(),
+            CallbackType::BeforeEvent | CallbackType::AfterEvent => self
+                .transitions
+                .keys()
+                .map(|k| Target::Name(Cow::Borrowed(k.event.as_ref())))
+                .collect(),
+            CallbackType::Transition => self
+                .transitions
+                .keys()
+                .map(|k| Target::Transition(Cow::Borrowed(&k.event), Cow::Borrowed(&k.src)))
+                .collect(),
+            CallbackType::OnError | CallbackType::Any | CallbackType::None => Vec::new(),
+        };
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+            .into_iter()
+            .filter(|target| self.find_target(callback_type, target).is_none())
+            .map(|target| target.to_string())
+            .collect()
+    }
+
+    /// require_hooks checks that every target has a hook of callback_type,
+    /// and fails with FSMError::MissingHooks listing the missing ones
+    /// otherwise. It is meant to be chained after new.
+    pub fn require_hooks(self, callback_type: CallbackType) -> Result<Self, FSMError<String>> {
+        let missing = self.missing_hooks(callback_type);
+        if missing.is_empty() {
+            return Ok(self);
+        }
+        Err(FSMError::MissingHooks(
+            missing
+                .into_iter()
+                .map(|target| {
+                    CKey {
+                        target: Target::Name(Cow::Owned(target)),
+                        callback_type,
+                    }
+                    .to_string()
+                })
+                .collect(),
+        ))
+    }
+
+    /// prune_unreachable removes the transitions whose source state cannot be
+    /// reached from the current state, and reports what was removed.
+    //
+    // Call it right after new to keep the tables of a generated machine
+    // minimal; at that point the current state is the initial state. A
+    // chooser can route its event to any state, so nothing is pruned once an
+    // event with a chooser can fire.
+    pub fn prune_unreachable(&mut self) -> Result<PruneReport, FSMError<String>> {
+        self.check_frozen()?;
+        // the wildcard source "" is always reachable
+        let mut reachable = HashSet::new();
+        let mut queue = vec![self.current.to_string(), String::new()];
+        while let Some(state) = queue.pop() {
+            if !reachable.insert(state.clone()) {
+                continue;
+            }
+            // transitions of ancestors fire from their descendants
+            if let Some(parent) = self.parents.get(&state) {
+                queue.push(parent.clone());
+            }
+            for (k, dst) in self.transitions.iter() {
+                if k.src == state.as_str() {
+                    if self.choosers.contains_key(k.event.as_ref()) {
+                        return Ok(PruneReport::default());
+                    }
+                    queue.push(dst.to_string());
+                }
+            }
+        }
+
+        let mut report = PruneReport::default();
+        let mut states = HashSet::new();
+        for (k, dst) in self.transitions.iter() {
+            if !reachable.contains(k.src.as_ref()) {
+                report
+                    .transitions
+                    .push((k.event.to_string(), k.src.to_string()));
+                states.insert(k.src.to_string());
+            }
+            states.insert(dst.to_string());
+        }
+        if report.transitions.is_empty() {
+            return Ok(report);
+        }
+
+        Arc::make_mut(&mut self.transitions).retain(|k, _| reachable.contains(k.src.as_ref()));
+        report.states = states
+            .into_iter()
+            .filter(|state| !reachable.contains(state))
+            .collect();
+        report.states.sort();
+        report.transitions.sort();
+        Ok(report)
+    }
+
+    /// checkpoint saves the current state, the sequence, the deferred
+    /// transition and the queued events, along with a hash of the context.
+    /// Only the most recent checkpoints are kept, see set_checkpoint_capacity.
+    pub fn checkpoint(&mut self)
+    where
+        C: Hash,
+    {
+        if self.checkpoint_capacity == 0 {
+            return;
+        }
+        if self.checkpoints.len() == self.checkpoint_capacity {
+            self.checkpoints.pop_front();
+        }
+        let context = hash_context(&*self.get_context());
+        self.checkpoints.push_back(Checkpoint {
+            current: self.current.clone(),
+            sequence: self.sequence,
+            pending: self.pending.clone(),
+            queued: self.queued.clone(),
+            context,
+        });
+    }
+
+    /// restore_checkpoint restores the k-th most recent checkpoint, where 0
+    /// is the latest one. No callbacks are run. It fails with
+    /// FSMError::NoCheckpoint if there is no such checkpoint.
+    //
+    // The context is not part of the checkpoint: the caller restores it
+    // first, and restore_checkpoint fails with FSMError::ContextMismatch if
+    // it does not hash like it did at the time of the checkpoint.
+    pub fn restore_checkpoint(&mut self, k: usize) -> Result<(), FSMError<String>>
+    where
+        C: Hash,
+    {
+        let len = self.checkpoints.len();
+        if k >= len {
+            return Err(FSMError::NoCheckpoint(k));
+        }
+        let checkpoint = &self.checkpoints[len - 1 - k];
+        if checkpoint.context != hash_context(&*self.get_context()) {
+            return Err(FSMError::ContextMismatch(k));
+        }
+        let checkpoint = checkpoint.clone();
+        self.current = checkpoint.current;
+        self.sequence = checkpoint.sequence;
+        self.pending = checkpoint.pending;
+        self.queued = checkpoint.queued;
+        self.previous = None;
+        self.release_resources();
+        Ok(())
+    }
+
+    /// set_checkpoint_capacity sets how many checkpoints are kept, dropping
+    /// the oldest ones if needed. It defaults to 16.
+    pub fn set_checkpoint_capacity(&mut self, capacity: usize) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        self.checkpoint_capacity = capacity;
+        while self.checkpoints.len() > capacity {
+            self.checkpoints.pop_front();
+        }
+        Ok(())
+    }
+
+    /// set_history_capacity sets how many of the last events are kept in the
+    /// history, dropping the oldest ones if needed. It defaults to 0, which
+    /// disables the history.
+    pub fn set_history_capacity(&mut self, capacity: usize) -> Result<(), FSMError<String>> {
+        self.check_frozen()?;
+        self.history_capacity = capacity;
+        while self.history.len() > capacity {
+            self.history.pop_front();
+        }
+        Ok(())
+    }
+
+    /// history iterates over the last events handled by the FSM, oldest
+    /// first: when they happened, the transition, and the error if they
+    /// failed, in which case dst is the state the FSM was left in.
+    //
+    // Events fired with on_event and its variants are recorded, as well as
+    // the transitions completed by transition and force_event.
+    pub fn history(&self) -> impl Iterator<Item = &HistoryEntry<S>> {
+        self.history.iter()
+    }
+
+    /// sandbox returns a cheap copy of the FSM that only runs its guards,
+    /// the BeforeEvent and LeaveState callbacks, so planners can explore
+    /// moves ahead without side effects and then apply the chosen event to
+    /// the real FSM.
+    //
+    // The sandbox shares the tables of the FSM, and gets a copy of its
+    // metadata and context, so writes made by its callbacks do not reach the
+    // FSM. The metadata is copied on write; Event::once runs nothing in the
+    // sandbox. It starts with no posted events, timers, history, checkpoints
+    // or held resources of its own. Args transformers and choosers still run
+    // in the sandbox; Any callbacks do not.
+    pub fn sandbox(&self) -> Self
+    where
+        C: Clone,
+    {
+        Self {
+            _marker: std::marker::PhantomData,
+            current: self.current.clone(),
+            transitions: self.transitions.clone(),
+            callbacks: self.callbacks.clone(),
+            hook_cache: self.hook_cache.clone(),
+            transformers: self.transformers.clone(),
+            choosers: self.choosers.clone(),
+            frozen: self.frozen,
+            fallback: self.fallback.clone(),
+            parents: self.parents.clone(),
+            groups: self.groups.clone(),
+            finals: self.finals.clone(),
+            externals: self.externals.clone(),
+            deferrable: self.deferrable.clone(),
+            queued: self.queued.clone(),
+            sequence: self.sequence,
+            dedup: self.dedup.clone(),
+            profiler: None,
+            summarize: self.summarize,
+            capture_entries: false,
+            summarize_errors: self.summarize_errors,
+            clone_args: self.clone_args,
+            entries: HashMap::new(),
+            poison_on_dirty: self.poison_on_dirty,
+            poisoned: self.poisoned,
+            hook_policy: self.hook_policy.clone(),
+            checkpoints: VecDeque::new(),
+            checkpoint_capacity: self.checkpoint_capacity,
+            sandboxed: true,
+            transaction: None,
+            pending: self.pending.clone(),
+            previous: self.previous.clone(),
+            forced: VecDeque::new(),
+            forced_capacity: self.forced_capacity,
+            history: VecDeque::new(),
+            history_capacity: 0,
+            metadata: Arc::new(self.metadata.sandboxed()),
+            context: Arc::new(Mutex::new(self.get_context().clone())),
+            posted: Arc::default(),
+            queue_policy: self.queue_policy,
+            no_transition_policy: self.no_transition_policy,
+            resources: Arc::default(),
+            clock: self.clock,
+            timers: Vec::new(),
+        }
+    }
+
+    /// is_sandboxed returns true if the FSM was created by sandbox.
+    pub fn is_sandboxed(&self) -> bool {
+        self.sandboxed
+    }
+
+    /// describe returns a human readable summary of the FSM: the current
+    /// state, the sorted transition table and the registered hooks.
+    pub fn describe(&self) -> String {
+        self.to_string()
+    }
+
+    /// is returns true if state is the current state.
+    pub fn is<T: AsRef<S>>(&self, state: T) -> bool {
+        self.current.eq(state.as_ref())
+    }
+
+    /// can returns true if event can occur in the current state.
+    pub fn can<T: AsRef<str>>(&self, event: T) -> bool {
+        self.lookup(event.as_ref(), self.current.as_ref()).is_some()
+    }
+
+    /// transitions returns an iterator over the transition table as
+    /// (event, src, dst) triples, ordered by event and then by source state.
+    //
+    // A wildcard source, which matches every state, is yielded as "".
+    pub fn transitions(&self) -> impl Iterator<Item = (&str, &str, &S)> {
+        self.transitions
+            .iter()
+            .map(|(k, dst)| (k.event.as_ref(), k.src.as_ref(), dst))
+    }
+
+    /// available_transitions returns the events that can occur in the current
+    /// state, sorted by name.
+    //
+    // Events with a wildcard source or declared on an ancestor of the current
+    // state are included.
+    pub fn available_transitions(&self) -> Vec<&str> {
+        let states: Vec<&str> = self.ancestors(self.current.as_ref()).collect();
+        let mut events: Vec<&str> = self
+            .transitions
+            .keys()
+            .filter(|k| k.src.is_empty() || states.contains(&k.src.as_ref()))
+            .map(|k| k.event.as_ref())
+            .collect();
+        events.dedup();
+        events
+    }
+}
+
+impl<'a, S, I, F, C> FSM<'a, S, I, F, C>
+where
+    S: FSMState,
+    F: Action<S, I, C>,
+{
+    // lookup returns the declared source and the destination of event from
+    // src or else from its nearest ancestor, falling back to the wildcard
+    // transition of event, declared from "", if there is none.
+    #[inline]
+    fn lookup<'k>(&'k self, event: &'k str, src: &'k str) -> Option<(&'k str, &'k S)> {
+        let transitions: &BTreeMap<EKey, S> = &self.transitions;
+        self.ancestors(src).chain([""]).find_map(|src| {
+            transitions
+                .get_key_value(&EKey {
+                    event: Cow::Borrowed(event),
+                    src: Cow::Borrowed(src),
+                })
+                .map(|(k, dst)| (k.src.as_ref(), dst))
+        })
+    }
+
+    // is_deferrable returns true if event is deferrable in the current state.
+    fn is_deferrable(&self, event: &str) -> bool {
+        let deferrable: &BTreeSet<EKey> = &self.deferrable;
+        !deferrable.is_empty()
+            && self.ancestors(self.current.as_ref()).any(|state| {
+                deferrable.contains(&EKey {
+                    event: Cow::Borrowed(event),
+                    src: Cow::Borrowed(state),
+                })
+            })
+    }
+
+    // check_frozen fails with FSMError::Frozen if the configuration is frozen.
+    fn check_frozen(&self) -> Result<(), FSMError<String>> {
+        if self.frozen {
+            return Err(FSMError::Frozen);
+        }
+        Ok(())
+    }
+
+    // queue queues event, which is deferrable in the current state, until a
+    // state accepts it.
+    fn queue(&mut self, event: &str, args: Option<&I>) -> Result<EventRecord<S>, FSMError<String>> {
+        let args = match (args, self.clone_args) {
+            (Some(args), Some(clone_args)) => Some(Arc::new(clone_args(args))),
+            _ => None,
+        };
+        self.queued.push_back(Queued {
+            event: event.to_string(),
+            args,
+            priority: 0,
+        });
+        Err(FSMError::Queued(event.to_string()))
+    }
+
+    // summarized attaches the summary of args to err, if error summaries are
+    // enabled.
+    fn summarized(&self, err: FSMError<String>, args: Option<&I>) -> FSMError<String> {
+        match (self.summarize_errors, self.summarize) {
+            (true, Some(summarize)) => {
+                let summary = args.map_or_else(|| "none".to_string(), summarize);
+                FSMError::WithArgs(summary, Box::new(err))
+            }
+            _ => err,
+        }
+    }
+
+    // fall_back handles event, which has no transition from the current
+    // state, with the fallback handler if any.
+    fn fall_back(
+        &mut self,
+        event: &str,
+        args: Option<&I>,
+        sequence: Option<u64>,
+    ) -> Result<EventRecord<S>, FSMError<String>> {
+        let fallback = match &self.fallback {
+            Some(fallback) => fallback(&self.current, event),
+            None => Fallback::Reject,
+        };
+        match fallback {
+            Fallback::Reject => {
+                if self.transitions.keys().any(|ekey| ekey.event == event) {
+                    Err(FSMError::InvalidEvent(
+                        event.to_string(),
+                        self.current.to_string(),
+                    ))
+                } else {
+                    Err(FSMError::UnknownEvent(event.to_string()))
+                }
+            }
+            Fallback::Ignore => Ok(EventRecord {
+                event: event.to_string(),
+                src: self.current.clone(),
+                dst: self.current.clone(),
+            }),
+            Fallback::Translate(translated) => {
+                let fallback = self.fallback.take();
+                let ret = self.apply(&translated, args, sequence);
+                self.fallback = fallback;
+                ret
+            }
+        }
+    }
+
+    // push_history records a transition, or a failed event with its error,
+    // in the history.
+    fn push_history(&mut self, record: EventRecord<S>, error: Option<String>) {
+        if self.history_capacity == 0 {
+            return;
+        }
+        if self.history.len() == self.history_capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(HistoryEntry {
+            at: self.clock,
+            time: SystemTime::now(),
+            record,
+            error,
+        });
+    }
+
+    // report_error runs the OnError callback, if any, for err returned by
+    // event. Its own failure is ignored, so err is still returned.
+    fn report_error(&self, event: &str, args: Option<&I>, err: &FSMError<String>) {
+        let Some(i) = self.find(CallbackType::OnError, "") else {
+            return;
+        };
+        let e = Event::new(
+            event,
+            &self.current,
+            &self.current,
+            args,
+            &self.metadata,
+            &self.posted,
+            &self.resources,
+            &self.context,
+        );
+        e.set_error(err);
+        let _ = self.run_hooks(CallbackType::OnError, &[i], &e, &mut Vec::new());
+    }
+
+    // release_resources drops the resources held for the states the FSM is
+    // no longer in.
+    fn release_resources(&self) {
+        if !self.resources.is_empty() {
+            let states: Vec<&str> = self.ancestors(self.current.as_ref()).collect();
+            self.resources.release(&states);
+        }
+    }
+
+    // replay_queued replays the queued events that the current state
+    // accepts, oldest first, until there are none left.
+    fn replay_queued(&mut self) {
+        while let Some(i) = self.queued.iter().position(|q| self.can(&q.event)) {
+            if let Some(q) = self.queued.remove(i) {
+                // a replayed event replays the rest itself once it succeeds
+                let _ = self.on_event(&q.event, q.args.as_deref());
+            }
+        }
+    }
+
+    #[inline]
+    fn poison_if_dirty(&mut self, e: &Event<S, I, C>) {
+        if self.poison_on_dirty && e.is_dirty() {
+            self.poisoned = true;
+        }
+    }
+
+    // enter moves the FSM to the destination of e and runs the EnterState and
+    // AfterEvent callbacks.
+    fn enter(
+        &mut self,
+        e: &Event<S, I, C>,
+        hooks: &HookSet,
+        trace: &mut Vec<String>,
+    ) -> Result<(), FSMError<String>> {
+        if let Err(err) = self.run_hooks(CallbackType::Transition, &hooks.transition, e, trace) {
+            self.hook_failed(
+                CallbackType::Transition,
+                e,
+                FSMError::InternalError(err.to_string()),
+                trace,
+            )?;
+        }
+        self.current = e.dst.clone();
+        let previous = self.previous.replace(e.src.clone());
+        let mut entry = None;
+        if let (true, Some(summarize)) = (self.capture_entries, self.summarize) {
+            let info = EntryInfo {
+                record: e.to_record(),
+                args: e.args.map(|args| truncate_summary(summarize(args))),
+            };
+            entry = Some(self.entries.insert(self.current.to_string(), info));
+        }
+        if self.buffer(StepKind::Entered, e, &hooks.enter_state, &hooks.after_event) {
+            return Ok(());
+        }
+        let mut left = Some(self.detach_left(e.src, e.dst));
+
+        for (callback_type, hooks) in [
+            (CallbackType::EnterState, &hooks.enter_state),
+            (CallbackType::AfterEvent, &hooks.after_event),
+        ] {
+            if let Err(err) = self.run_hooks(callback_type, hooks, e, trace) {
+                let err = FSMError::InternalError(err.to_string());
+                let ret = self.hook_failed(callback_type, e, err, trace);
+                if ret.is_err() && self.hook_policy.get(&callback_type) == FailurePolicy::Rollback {
+                    // hook_failed restored current, undo the rest of entering:
+                    // drop what dst acquired and give src its resources back
+                    drop(self.detach_left(e.src, e.dst));
+                    if let Some(left) = left.take() {
+                        self.resources.reattach(left);
+                    }
+                    self.previous = previous.clone();
+                    match entry.take() {
+                        Some(Some(info)) => {
+                            self.entries.insert(e.dst.to_string(), info);
+                        }
+                        Some(None) => {
+                            self.entries.remove(&e.dst.to_string());
+                        }
+                        None => {}
+                    }
+                }
+                ret?;
+            }
+        }
+        Ok(())
+    }
+
+    // detach_left takes out the resources held for the states left by moving
+    // from src to dst. They are dropped with the returned Released, once the
+    // callbacks entering dst succeeded, or given back if the move is rolled
+    // back.
+    fn detach_left(&self, src: &S, dst: &S) -> Released {
+        let skip = usize::from(src == dst);
+        // an external self-transition left dst, but not its ancestors
+        let states: Vec<&str> = self.ancestors(dst.as_ref()).skip(skip).collect();
+        self.resources.detach(&states)
+    }
+
+    // buffer buffers the EnterState and AfterEvent callbacks of e as a step
+    // of the open transaction, and returns true, if there is one.
+    fn buffer(
+        &mut self,
+        kind: StepKind,
+        e: &Event<S, I, C>,
+        enter_state: &[usize],
+        after_event: &[usize],
+    ) -> bool {
+        let Some(transaction) = self.transaction.as_mut() else {
+            return false;
+        };
+        transaction.steps.push(Step {
+            kind,
+            event: e.event.to_string(),
+            src: e.src.clone(),
+            dst: e.dst.clone(),
+            args: e.args.map(transaction.clone),
+            sequence: e.sequence(),
+            enter_state: enter_state.to_vec(),
+            after_event: after_event.to_vec(),
+        });
+        true
+    }
+
+    // commit runs the callbacks buffered by a transaction, step by step. A
+    // failure is handled by the hook policy, except in a Stayed step, which
+    // fails like on_event does. The resources of the states left are given
+    // back if commit fails, since transact then rolls the transaction back.
+    fn commit(&mut self, steps: Vec<Step<S, I>>) -> Result<(), FSMError<String>> {
+        let mut left = Vec::new();
+        for step in &steps {
+            if let Err(err) = self.commit_step(step, &mut left) {
+                for released in left.into_iter().rev() {
+                    self.resources.reattach(released);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // commit_step runs the callbacks buffered for step, taking out the
+    // resources of the states it left into left first.
+    fn commit_step(
+        &mut self,
+        step: &Step<S, I>,
+        left: &mut Vec<Released>,
+    ) -> Result<(), FSMError<String>> {
+        let metadata = self.metadata.clone();
+        let posted = self.posted.clone();
+        let resources = self.resources.clone();
+        let context = self.context.clone();
+        let e = Event::new(
+            &step.event,
+            &step.src,
+            &step.dst,
+            step.args.as_ref(),
+            &metadata,
+            &posted,
+            &resources,
+            &context,
+        );
+        e.set_sequence(step.sequence);
+        if step.kind == StepKind::Entered {
+            left.push(self.detach_left(&step.src, &step.dst));
+        }
+        let mut trace = Vec::new();
+        for (callback_type, hooks) in [
+            (CallbackType::EnterState, &step.enter_state),
+            (CallbackType::AfterEvent, &step.after_event),
+        ] {
+            if let Err(err) = self.run_hooks(callback_type, hooks, &e, &mut trace) {
+                if step.kind == StepKind::Stayed {
+                    self.poison_if_dirty(&e);
+                    return Err(with_trace(
+                        FSMError::NoTransitionWithError(err.to_string()),
+                        trace,
+                    ));
+                }
+                let err = FSMError::InternalError(err.to_string());
+                self.hook_failed(callback_type, &e, err, &trace)?;
+            }
+        }
+        Ok(())
+    }
+
+    // restore puts snapshot, taken by transact, back in place of the FSM. The
+    // held resources and the profile of the callbacks that ran are kept.
+    fn restore(&mut self, mut snapshot: Self) {
+        snapshot.resources = self.resources.clone();
+        snapshot.profiler = self.profiler.take();
+        *self = snapshot;
+    }
+
+    // hook_failed applies the hook policy of callback_type to a failed
+    // callback. It returns Ok if the failure is ignored.
+    fn hook_failed(
+        &mut self,
+        callback_type: CallbackType,
+        e: &Event<S, I, C>,
+        err: FSMError<String>,
+        trace: &[String],
+    ) -> Result<(), FSMError<String>> {
+        self.poison_if_dirty(e);
+        match self.hook_policy.get(&callback_type) {
+            FailurePolicy::Ignore => return Ok(()),
+            FailurePolicy::Abort => {}
+            FailurePolicy::Rollback => {
+                self.current = e.src.clone();
+                self.release_resources();
+            }
+            FailurePolicy::Poison => self.poisoned = true,
+        }
+        Err(with_trace(err, trace.to_vec()))
+    }
+
+    // cached_hooks returns the hooks that fire when event, declared from
+    // declared, moves the FSM from src to dst, resolving and caching them on
+    // first use.
+    //
+    // Entries are keyed by (src, event); the declared source and destination
+    // are stored alongside so an entry resolved for others is resolved again.
+    fn cached_hooks(&mut self, src: &str, event: &str, declared: &str, dst: &str) -> Arc<HookSet> {
+        if let Some((cached_declared, cached_dst, hooks)) =
+            self.hook_cache.entries.get(src).and_then(|m| m.get(event))
+        {
+            if cached_declared == declared && cached_dst == dst {
+                self.hook_cache.hits += 1;
+                return hooks.clone();
+            }
+        }
+        self.hook_cache.misses += 1;
+
+        let mut hooks = self.hook_set(event, Some(src), dst);
+        hooks.transition = self
+            .find_target(
+                CallbackType::Transition,
+                &Target::Transition(Cow::Borrowed(event), Cow::Borrowed(declared)),
+            )
+            .into_iter()
+            .chain(self.find(CallbackType::Any, ""))
+            .collect();
+        let hooks = Arc::new(hooks);
+        Arc::make_mut(&mut self.hook_cache.entries)
+            .entry(src.to_string())
+            .or_default()
+            .insert(
+                event.to_string(),
+                (declared.to_string(), dst.to_string(), hooks.clone()),
+            );
+        hooks
+    }
+
+    // hook_set resolves the hooks that fire when event moves the FSM from src
+    // to dst, except the Transition hooks, which depend on the transition
+    // that matched, see cached_hooks. An empty event resolves only the
+    // LeaveState and EnterState hooks, and no src enters dst and all its
+    // ancestors.
+    //
+    // The states left are src and its ancestors, innermost first, and the
+    // states entered are dst and its ancestors, outermost first, up to their
+    // nearest common ancestor, which is neither left nor entered. Groups are
+    // left after the states and entered before them, and only if the other
+    // side of the transition is not in the group. Targeted hooks run before
+    // the hook for every target.
+    fn hook_set(&self, event: &str, src: Option<&str>, dst: &str) -> HookSet {
+        let src_chain: Vec<&str> = src
+            .into_iter()
+            .flat_map(|src| self.ancestors(src))
+            .collect();
+        let dst_chain: Vec<&str> = self.ancestors(dst).collect();
+        let common = src_chain
+            .iter()
+            .skip(1)
+            .find(|state| dst_chain[1..].contains(state))
+            .copied();
+        let left = src_chain.iter().take_while(|state| Some(**state) != common);
+        let entered: Vec<&str> = dst_chain
+            .iter()
+            .take_while(|state| Some(**state) != common)
+            .copied()
+            .collect();
+        let groups = |chain: &[&str]| -> BTreeSet<&str> {
+            chain
+                .iter()
+                .filter_map(|state| self.groups.get(*state))
+                .flatten()
+                .map(String::as_str)
+                .collect()
+        };
+        let (src_groups, dst_groups) = (groups(&src_chain), groups(&dst_chain));
+        let name = |name| Target::Name(Cow::Borrowed(name));
+        let group = |group| Target::Group(Cow::Borrowed(group));
+        let left_groups = src_groups.difference(&dst_groups).copied().map(group);
+        let entered_groups = dst_groups.difference(&src_groups).copied().map(group);
+
+        let any = self.find(CallbackType::Any, "");
+        let hooks = |callback_type, targets: &mut dyn Iterator<Item = Target>| -> Vec<usize> {
+            targets
+                .chain([Target::any()])
+                .filter_map(|target| self.find_target(callback_type, &target))
+                .chain(any)
+                .collect()
+        };
+        let mut hook_set = HookSet {
+            leave_state: hooks(
+                CallbackType::LeaveState,
+                &mut left.copied().map(name).chain(left_groups),
+            ),
+            enter_state: hooks(
+                CallbackType::EnterState,
+                &mut entered_groups.chain(entered.into_iter().rev().map(name)),
+            ),
+            ..HookSet::default()
+        };
+        if !event.is_empty() {
+            hook_set.before_event =
+                hooks(CallbackType::BeforeEvent, &mut [name(event)].into_iter());
+            hook_set.after_event = hooks(CallbackType::AfterEvent, &mut [name(event)].into_iter());
+        }
+        hook_set
+    }
+
+    // find returns the position of the callback of callback_type registered
+    // for target, if any.
+    fn find(&self, callback_type: CallbackType, target: &str) -> Option<usize> {
+        self.find_target(callback_type, &Target::Name(Cow::Borrowed(target)))
+    }
+
+    // find_target is find for any kind of target.
+    fn find_target(&self, callback_type: CallbackType, target: &Target) -> Option<usize> {
+        self.callbacks
+            .binary_search_by(|(k, _)| (k.callback_type, &k.target).cmp(&(callback_type, target)))
+            .ok()
+    }
+
+    // ancestors iterates over state and then its ancestors, innermost first.
+    fn ancestors<'k>(&'k self, state: &'k str) -> impl Iterator<Item = &'k str> {
+        std::iter::successors(Some(state), |state| {
+            self.parents.get(*state).map(String::as_str)
+        })
+    }
+
+    // run_hooks runs the callbacks of phase at the given positions, recording
+    // each in trace once it has succeeded.
+    #[inline]
+    fn run_hooks(
+        &self,
+        phase: CallbackType,
+        hooks: &[usize],
+        e: &Event<S, I, C>,
+        trace: &mut Vec<String>,
+    ) -> Result<(), F::Err> {
+        for &i in hooks {
+            let (key, f) = &self.callbacks[i];
+            // a sandbox only runs the guards
+            if self.sandboxed
+                && (key.callback_type == CallbackType::Any
+                    || !matches!(phase, CallbackType::BeforeEvent | CallbackType::LeaveState))
+            {
+                continue;
+            }
+            // a canceled transition runs no further before/leave callbacks
+            if e.is_canceled()
+                && matches!(phase, CallbackType::BeforeEvent | CallbackType::LeaveState)
+            {
+                return Ok(());
+            }
+            e.set_phase(phase);
+            match &self.profiler {
+                Some(profiler) => {
+                    let started = Instant::now();
+                    let ret = f.call(e);
+                    let stack = match e.event {
+                        "" => key.to_string(),
+                        event => format!("{};{}", event, key),
+                    };
+                    profiler.record(stack, started.elapsed());
+                    ret?;
+                }
+                None => f.call(e)?,
+            }
+            trace.push(key.to_string());
+        }
+        Ok(())
+    }
+}
+
+// truncate_summary truncates summary to at most MAX_ENTRY_SUMMARY bytes, on a
+// char boundary.
+fn truncate_summary(mut summary: String) -> String {
+    if summary.len() > MAX_ENTRY_SUMMARY {
+        let end = (0..=MAX_ENTRY_SUMMARY)
+            .rev()
+            .find(|&i| summary.is_char_boundary(i))
+            .unwrap_or(0);
+        summary.truncate(end);
+    }
+    summary
+}
+
+// hash_context hashes context, for the checkpoints.
+fn hash_context<C: Hash>(context: &C) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    context.hash(&mut hasher);
+    hasher.finish()
+}
+
+// with_trace wraps a hook error with the hooks that had already run during the
+// transition. Errors raised before any hook ran are returned unchanged.
+fn with_trace(err: FSMError<String>, trace: Vec<String>) -> FSMError<String> {
+    if trace.is_empty() {
+        err
+    } else {
+        FSMError::WithTrace(trace, Box::new(err))
+    }
+}
+
+impl<'a, S, I, F, C> Debug for FSM<'a, S, I, F, C>
+where
+    S: Display,
+    F: Action<S, I, C>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Transitions<'b, 'a, S>(&'b BTreeMap<EKey<'a>, S>);
+        impl<'b, 'a, S: Display> Debug for Transitions<'b, 'a, S> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_list()
+                    .entries(
+                        self.0
+                            .iter()
+                            .map(|(k, dst)| format!("{}: {} -> {}", k.event, k.src_label(), dst)),
+                    )
+                    .finish()
+            }
+        }
+
+        struct Hooks<'b, 'a, F>(&'b [(CKey<'a>, F)]);
+        impl<'b, 'a, F: Debug> Debug for Hooks<'b, 'a, F> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_map()
+                    .entries(self.0.iter().map(|(k, v)| (k.to_string(), v)))
+                    .finish()
+            }
+        }
+
+        f.debug_struct("FSM")
+            .field("current", &format_args!("{}", self.current))
+            .field("transitions", &Transitions(&self.transitions))
+            .field("hooks", &Hooks(&self.callbacks))
+            .finish()
+    }
+}
+
+impl<'a, S, I, F, C> Display for FSM<'a, S, I, F, C>
+where
+    S: Display,
+    F: Action<S, I, C>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "current: {}", self.current)?;
+        writeln!(f, "transitions:")?;
+        for (k, dst) in self.transitions.iter() {
+            writeln!(f, "  {}: {} -> {}", k.event, k.src_label(), dst)?;
+        }
+        write!(f, "hooks:")?;
+        for (k, _) in self.callbacks.iter() {
+            write!(f, "\n  {}", k)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallbackType, EventDesc, FSMState, HookCacheStats, HookType, PruneReport, FSM};
+    use crate::{
+        action::Closure,
+        error::FSMError,
+        event::{Bytes, EntryInfo, Event, EventRecord, Sequenced, Summarize},
+        policy::{FailurePolicy, Fallback, HookPolicy, NoTransitionPolicy},
+        Action,
+    };
+    use std::{
+        collections::HashMap,
+        fmt::{Debug, Display},
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, SystemTime},
+    };
+    use strum::AsRefStr;
+    use strum::Display;
+    use thiserror::Error;
+
+    #[derive(Debug, Error)]
+    enum MyError {
+        #[error("my error: {0}")]
+        CustomeError(&'static str),
+    }
+
+    #[derive(Display, AsRefStr, Debug, Clone, Hash, PartialEq, Eq)]
+    enum StateTag {
+        #[strum(serialize = "opened")]
+        Opened,
+        #[strum(serialize = "closed")]
+        Closed,
+    }
+    impl FSMState for StateTag {}
+    // kept as in the README example, which borrows self
+    #[allow(clippy::needless_borrow)]
+    impl AsRef<Self> for StateTag {
+        fn as_ref(&self) -> &Self {
+            &self
+        }
+    }
+
+    #[derive(Display, AsRefStr, Debug, Clone, Hash, PartialEq, Eq)]
+    enum EventTag {
+        #[strum(serialize = "open")]
+        Open,
+        #[strum(serialize = "close")]
+        Close,
+    }
+
+    type FSMWithHashMap<'a> =
+        FSM<'a, StateTag, HashMap<u32, u32>, Closure<'a, StateTag, HashMap<u32, u32>, MyError>>;
+    type FSMWithVec<'a> = FSM<'a, StateTag, Vec<u32>, Closure<'a, StateTag, Vec<u32>, MyError>>;
+
+    // door returns the events of the machine most tests run: open moves it
+    // from closed to opened, and close back.
+    fn door() -> [(EventTag, StateTag, StateTag); 2] {
+        [
+            (EventTag::Open, StateTag::Closed, StateTag::Opened),
+            (EventTag::Close, StateTag::Opened, StateTag::Closed),
+        ]
+    }
+
+    #[test]
+    fn test_fsm_state() {
+        {
+            let mut fsm: FSMWithHashMap = FSM::new(
+                StateTag::Closed,
+                vec![
+                    EventDesc {
+                        name: EventTag::Open,
+                        src: vec![StateTag::Closed],
+                        dst: StateTag::Opened,
+                    },
+                    EventDesc {
+                        name: EventTag::Close,
+                        src: vec![StateTag::Opened],
+                        dst: StateTag::Closed,
+                    },
+                ],
+                HashMap::new(),
+            );
+            assert_eq!(StateTag::Closed, fsm.get_current());
+            assert!(fsm.is(StateTag::Closed));
+            assert!(fsm.is(&StateTag::Closed));
+
+            assert!(fsm.can(EventTag::Open));
+            assert!(fsm.on_event("open", Some(&HashMap::new())).is_ok());
+            assert_eq!(StateTag::Opened, fsm.get_current());
+            assert!(fsm.is(StateTag::Opened));
+            assert!(fsm.is(&StateTag::Opened));
+
+            assert!(fsm.can(EventTag::Close));
+            assert!(fsm.on_event("close", Some(&HashMap::new())).is_ok());
+            assert_eq!(StateTag::Closed, fsm.get_current());
+            assert!(fsm.is(StateTag::Closed));
+            assert!(fsm.is(&StateTag::Closed));
+
+            assert!(!fsm.can(EventTag::Close));
+            let ret = fsm.on_event("close", None);
+            assert!(ret.is_err());
+            assert_eq!(
+                ret.err().unwrap(),
+                FSMError::InvalidEvent("close".to_string(), StateTag::Closed.to_string())
+            );
+            assert_eq!(StateTag::Closed, fsm.get_current());
+            assert!(fsm.is(StateTag::Closed));
+        }
+
+        {
+            let mut fsm: FSMWithVec = FSM::new(
+                StateTag::Closed,
+                vec![
+                    EventDesc {
+                        name: EventTag::Open,
+                        src: vec![StateTag::Closed],
+                        dst: StateTag::Opened,
+                    },
+                    EventDesc {
+                        name: EventTag::Close,
+                        src: vec![StateTag::Opened],
+                        dst: StateTag::Closed,
+                    },
+                ],
+                HashMap::new(),
+            );
+            assert_eq!(StateTag::Closed, fsm.get_current());
+
+            assert!(fsm.on_event("open", Some(&Vec::new())).is_ok());
+            assert_eq!(StateTag::Opened, fsm.get_current());
+
+            assert!(fsm.on_event("close", Some(&Vec::new())).is_ok());
+            assert_eq!(StateTag::Closed, fsm.get_current());
+
+            let ret = fsm.on_event("close", None);
+            assert!(ret.is_err());
+            assert_eq!(
+                ret.err().unwrap(),
+                FSMError::InvalidEvent("close".to_string(), "closed".to_string())
+            );
+            assert_eq!(StateTag::Closed, fsm.get_current());
+        }
+
+        {
+            let mut fsm: FSMWithVec = FSM::new(
+                StateTag::Closed,
+                vec![
+                    EventDesc {
+                        name: EventTag::Open,
+                        src: vec![StateTag::Closed],
+                        dst: StateTag::Opened,
+                    },
+                    EventDesc {
+                        name: EventTag::Close,
+                        src: vec![StateTag::Opened],
+                        dst: StateTag::Closed,
+                    },
+                ],
+                vec![(
+                    HookType::<EventTag, StateTag>::BeforeEvent,
+                    Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+                )],
+            );
+            assert_eq!(StateTag::Closed, fsm.get_current());
+
+            assert!(fsm.on_event("open", Some(&Vec::new())).is_ok());
+            assert_eq!(StateTag::Opened, fsm.get_current());
+
+            assert!(fsm.on_event("close", Some(&Vec::new())).is_ok());
+            assert_eq!(StateTag::Closed, fsm.get_current());
+
+            let ret = fsm.on_event("close", None);
+            assert!(ret.is_err());
+            assert_eq!(
+                ret.err().unwrap(),
+                FSMError::InvalidEvent("close".to_string(), "closed".to_string())
+            );
+            assert_eq!(StateTag::Closed, fsm.get_current());
+        }
+    }
+
+    #[test]
+    fn test_fsm_effect_once() {
+        let counter = AtomicU32::new(0);
+        let build = || -> FSMWithVec {
+            FSM::new(
+                StateTag::Closed,
+                door(),
+                HashMap::from([(
+                    HookType::Enter(StateTag::Opened),
+                    Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                        e.once("notify", || {
+                            counter.fetch_add(1, Ordering::Relaxed);
+                        });
+                        Ok(())
+                    }),
+                )]),
+            )
+        };
+        let log = [
+            (1, EventTag::Open),
+            (2, EventTag::Close),
+            (3, EventTag::Open),
+        ];
+
+        let mut fsm = build();
+        for (seq, event) in &log {
+            assert!(fsm.on_event(Sequenced(*seq, event), None).is_ok());
+        }
+        assert_eq!(2, counter.load(Ordering::Relaxed));
+        let effects = fsm.get_effects();
+        assert_eq!(
+            vec![(1, "notify".to_string()), (3, "notify".to_string())],
+            effects
+        );
+
+        // rebuilding the FSM from the log does not notify again
+        let mut fsm = build();
+        fsm.set_effects(effects);
+        for (seq, event) in &log {
+            assert!(fsm.on_event(Sequenced(*seq, event), None).is_ok());
+        }
+        assert_eq!(2, counter.load(Ordering::Relaxed));
+        assert_eq!(StateTag::Opened, fsm.get_current());
+
+        // unsequenced events are not tracked
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(3, counter.load(Ordering::Relaxed));
+        assert_eq!(2, fsm.get_effects().len());
+
+        // acknowledged effects are forgotten
+        fsm.ack_effects(1);
+        assert_eq!(vec![(3, "notify".to_string())], fsm.get_effects());
+        fsm.ack_effects(u64::MAX);
+        assert!(fsm.get_effects().is_empty());
+    }
+
+    #[test]
+    fn test_fsm_sequenced_event() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        assert_eq!(None, fsm.get_sequence());
+
+        assert!(fsm.on_event(Sequenced(1, EventTag::Open), None).is_ok());
+        assert_eq!(Some(1), fsm.get_sequence());
+
+        // replaying an already applied event is rejected
+        let ret = fsm.on_event(Sequenced(1, EventTag::Open), None);
+        assert_eq!(ret.err().unwrap(), FSMError::StaleEvent(1, 1));
+        assert_eq!(StateTag::Opened, fsm.get_current());
+
+        // failed transitions do not advance the sequence
+        assert!(fsm.on_event(Sequenced(2, EventTag::Open), None).is_err());
+        assert_eq!(Some(1), fsm.get_sequence());
+
+        assert!(fsm.on_event(Sequenced(3, EventTag::Close), None).is_ok());
+        assert_eq!(Some(3), fsm.get_sequence());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+    }
+
+    impl Summarize for Vec<u32> {
+        fn summarize(&self) -> String {
+            format!("{} item(s)", self.len())
+        }
+    }
+
+    #[test]
+    fn test_fsm_last_entry_info() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        assert!(fsm.on_event(EventTag::Open, Some(&vec![1])).is_ok());
+        assert_eq!(None, fsm.last_entry_info(StateTag::Opened));
+
+        assert!(fsm.set_entry_capture(true).is_ok());
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        assert!(fsm.on_event(EventTag::Open, Some(&vec![1, 2])).is_ok());
+        assert_eq!(
+            Some(&EntryInfo {
+                record: EventRecord {
+                    event: "open".to_string(),
+                    src: StateTag::Closed,
+                    dst: StateTag::Opened,
+                },
+                args: Some("2 item(s)".to_string()),
+            }),
+            fsm.last_entry_info(StateTag::Opened)
+        );
+        assert_eq!(None, fsm.last_entry_info(StateTag::Closed).unwrap().args);
+
+        assert!(fsm.set_entry_capture(false).is_ok());
+        assert_eq!(None, fsm.last_entry_info(StateTag::Opened));
+    }
+
+    #[test]
+    fn test_fsm_summarized_event() {
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            vec![EventDesc {
+                name: EventTag::Open,
+                src: vec![StateTag::Closed],
+                dst: StateTag::Opened,
+            }],
+            HashMap::new(),
+        );
+        assert!(fsm.set_error_summary(true).is_ok());
+        let ret = fsm.on_event(EventTag::Close, Some(&vec![1, 2]));
+        assert_eq!(
+            ret.err().unwrap(),
+            FSMError::WithArgs(
+                "2 item(s)".to_string(),
+                Box::new(FSMError::UnknownEvent("close".to_string()))
+            )
+        );
+
+        let ret = fsm.on_event(EventTag::Close, None);
+        assert_eq!(
+            ret.err().unwrap().to_string(),
+            "event close does not exist (args: none)"
+        );
+
+        // a sequenced event is summarized too
+        let ret = fsm.on_event(Sequenced(1, EventTag::Close), None);
+        assert!(matches!(ret, Err(FSMError::WithArgs(..))));
+        assert_eq!(None, fsm.get_sequence());
+
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+    }
+
+    #[test]
+    fn test_fsm_deduped_event() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+
+        assert!(fsm.set_dedup_window(Some(Duration::from_secs(60))).is_ok());
+        assert!(fsm.on_event(EventTag::Open, Some(&vec![1])).is_ok());
+        assert_eq!(
+            fsm.on_event(EventTag::Open, Some(&vec![1])).err().unwrap(),
+            FSMError::DuplicateEvent("open".to_string())
+        );
+        assert_eq!(1, fsm.get_suppressed_count());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+
+        // the window elapses in real time, without tick
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(fsm.set_dedup_window(Some(Duration::from_millis(1))).is_ok());
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        assert_eq!(0, fsm.get_suppressed_count());
+    }
+
+    #[test]
+    fn test_fsm_transformer() {
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::BeforeEvent,
+            Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                match e.event {
+                    "open" => assert_eq!(Some(&vec![1, 2, 3]), e.args),
+                    _ => assert_eq!(Some(&vec![3, 2, 1]), e.args),
+                }
+                Ok(())
+            }),
+        )]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        assert!(fsm
+            .set_transformer(EventTag::Open, |args: &Vec<u32>| {
+                let mut args = args.clone();
+                args.sort();
+                args
+            })
+            .is_ok());
+
+        assert!(fsm.on_event(EventTag::Open, Some(&vec![3, 1, 2])).is_ok());
+        assert!(fsm.on_event(EventTag::Close, Some(&vec![3, 2, 1])).is_ok());
+    }
+
+    #[test]
+    fn test_fsm_any_hook() {
+        let phases = Mutex::new(Vec::new());
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            [(EventTag::Open, StateTag::Closed, StateTag::Opened)],
+            [(
+                HookType::Any,
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    phases.lock().unwrap().push(e.phase());
+                    Ok(())
+                }),
+            )],
+        );
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(
+            vec![
+                CallbackType::BeforeEvent,
+                CallbackType::LeaveState,
+                CallbackType::Transition,
+                CallbackType::EnterState,
+                CallbackType::AfterEvent,
+            ],
+            *phases.lock().unwrap()
+        );
+        assert_eq!("any", CallbackType::Any.to_string());
+    }
+
+    #[test]
+    fn test_fsm_on_error_hook() {
+        let errors = Mutex::new(Vec::new());
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            [
+                (EventTag::Open, StateTag::Closed, StateTag::Opened),
+                (EventTag::Close, StateTag::Opened, StateTag::Closed),
+                (EventTag::Close, StateTag::Closed, StateTag::Closed),
+            ],
+            [
+                (
+                    HookType::OnError,
+                    Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                        assert_eq!(CallbackType::OnError, e.phase());
+                        let err = e.error().unwrap();
+                        errors
+                            .lock()
+                            .unwrap()
+                            .push((e.event.to_string(), err.code()));
+                        Err(MyError::CustomeError("ignored"))
+                    }),
+                ),
+                (
+                    HookType::Before(EventTag::Open),
+                    Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                        match e.args {
+                            Some(_) => Err(MyError::CustomeError("no args expected")),
+                            None => Ok(()),
+                        }
+                    }),
+                ),
+            ],
+        );
+        assert!(fsm.on_event("jump", None).is_err());
+        assert!(fsm.on_event(EventTag::Open, Some(&vec![1])).is_err());
+        assert_eq!(
+            Err(FSMError::NoTransition),
+            fsm.on_event(EventTag::Close, None)
+        );
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert!(fsm.on_event(EventTag::Open, None).is_err());
+        assert_eq!(
+            vec![
+                ("jump".to_string(), "unknown_event"),
+                ("open".to_string(), "internal_error"),
+                ("open".to_string(), "invalid_event"),
+            ],
+            *errors.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fsm_state_groups() {
+        let log = Mutex::new(Vec::new());
+        let record = |name: &'static str| {
+            let log = &log;
+            Closure::new(
+                move |_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    log.lock().unwrap().push(name);
+                    Ok(())
+                },
+            )
+        };
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            door(),
+            [
+                (HookType::EnterGroup("active"), record("enter active")),
+                (HookType::LeaveGroup("active"), record("leave active")),
+                (HookType::EnterGroup("any"), record("enter any")),
+                (HookType::Enter(StateTag::Opened), record("enter opened")),
+                (HookType::Leave(StateTag::Opened), record("leave opened")),
+            ],
+        );
+        assert!(fsm.set_group(StateTag::Opened, "active").is_ok());
+        assert!(fsm.set_group(StateTag::Opened, "any").is_ok());
+        assert!(fsm.set_group(StateTag::Closed, "any").is_ok());
+        assert!(!fsm.in_group("active"));
+        assert!(fsm.in_group("any"));
+
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert!(fsm.in_group("active"));
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        assert_eq!(
+            vec![
+                "enter active",
+                "enter opened",
+                "leave opened",
+                "leave active"
+            ],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fsm_group_named_like_state() {
+        #[derive(Display, AsRefStr, Debug, Clone, Hash, PartialEq, Eq)]
+        enum Zone {
+            #[strum(serialize = "idle")]
+            Idle,
+            #[strum(serialize = "group:active")]
+            Busy,
+        }
+        impl FSMState for Zone {}
+        impl AsRef<Self> for Zone {
+            fn as_ref(&self) -> &Self {
+                self
+            }
+        }
+
+        let log = Mutex::new(Vec::new());
+        let record = |label: &'static str| {
+            let log = &log;
+            Closure::new(move |_e: &Event<Zone, Vec<u32>>| -> Result<(), MyError> {
+                log.lock().unwrap().push(label);
+                Ok(())
+            })
+        };
+        let mut fsm: FSM<_, Vec<u32>, _> = FSM::new(
+            Zone::Idle,
+            [
+                ("work", Zone::Idle, Zone::Busy),
+                ("rest", Zone::Busy, Zone::Idle),
+            ],
+            [
+                (
+                    HookType::<&str, Zone>::Enter(Zone::Busy),
+                    record("enter busy"),
+                ),
+                (HookType::EnterGroup("active"), record("enter active")),
+            ],
+        );
+        assert!(fsm.set_group(Zone::Idle, "active").is_ok());
+
+        // the state and the group have hooks of their own
+        assert!(fsm.on_event("work", None).is_ok());
+        assert!(fsm.on_event("rest", None).is_ok());
+        assert_eq!(vec!["enter busy", "enter active"], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn test_fsm_transition_hook_names() {
+        #[derive(Display, AsRefStr, Debug, Clone, Hash, PartialEq, Eq)]
+        enum Node {
+            #[strum(serialize = "c")]
+            C,
+            #[strum(serialize = "b:c")]
+            BC,
+            #[strum(serialize = "d")]
+            D,
+        }
+        impl FSMState for Node {}
+        impl AsRef<Self> for Node {
+            fn as_ref(&self) -> &Self {
+                self
+            }
+        }
+
+        let counter = AtomicU32::new(0);
+        let mut fsm: FSM<_, Vec<u32>, _> = FSM::new(
+            Node::C,
+            [("a:b", Node::C, Node::D), ("a", Node::BC, Node::D)],
+            [(
+                HookType::<&str, Node>::Transition("a", Node::BC),
+                Closure::new(|_e: &Event<Node, Vec<u32>>| -> Result<(), MyError> {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            )],
+        );
+        assert_eq!(vec!["a:b:c"], fsm.missing_hooks(CallbackType::Transition));
+
+        // a:b from c is not a from b:c
+        assert!(fsm.on_event("a:b", None).is_ok());
+        assert_eq!(0, counter.load(Ordering::Relaxed));
+        fsm.set_state(Node::BC);
+        assert!(fsm.on_event("a", None).is_ok());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_fsm_nested_states() {
+        #[derive(Display, AsRefStr, Debug, Clone, Hash, PartialEq, Eq)]
+        #[strum(serialize_all = "snake_case")]
+        enum Light {
+            Off,
+            On,
+            Red,
+            Green,
+        }
+        impl FSMState for Light {}
+        impl AsRef<Self> for Light {
+            fn as_ref(&self) -> &Self {
+                self
+            }
+        }
+
+        let log = Mutex::new(Vec::new());
+        let record = |label: &'static str| {
+            let log = &log;
+            Closure::new(move |_e: &Event<Light, Vec<u32>>| -> Result<(), MyError> {
+                log.lock().unwrap().push(label);
+                Ok(())
+            })
+        };
+        let mut fsm: FSM<_, Vec<u32>, _> = FSM::new(
+            Light::Off,
+            [
+                ("power", Light::Off, Light::Red),
+                ("power", Light::On, Light::Off),
+                ("next", Light::Red, Light::Green),
+                ("next", Light::Green, Light::Red),
+            ],
+            [
+                (
+                    HookType::<&str, Light>::Leave(Light::Off),
+                    record("leave off"),
+                ),
+                (HookType::Enter(Light::Off), record("enter off")),
+                (HookType::Leave(Light::On), record("leave on")),
+                (HookType::Enter(Light::On), record("enter on")),
+                (HookType::Leave(Light::Red), record("leave red")),
+                (HookType::Enter(Light::Red), record("enter red")),
+                (HookType::Leave(Light::Green), record("leave green")),
+                (HookType::Enter(Light::Green), record("enter green")),
+                (
+                    HookType::Transition("power", Light::On),
+                    record("power off"),
+                ),
+            ],
+        );
+        assert!(fsm.set_parent(Light::Red, Light::On).is_ok());
+        assert!(fsm.set_parent(Light::Green, Light::On).is_ok());
+        assert_eq!(
+            Err(FSMError::InvalidParent("on".to_string(), "red".to_string())),
+            fsm.set_parent(Light::On, Light::Red)
+        );
+
+        assert!(fsm.on_event("power", None).is_ok());
+        assert_eq!(
+            vec!["leave off", "enter on", "enter red"],
+            *log.lock().unwrap()
+        );
+        log.lock().unwrap().clear();
+
+        assert!(fsm.on_event("next", None).is_ok());
+        assert_eq!(vec!["leave red", "enter green"], *log.lock().unwrap());
+        log.lock().unwrap().clear();
+
+        // the transition declared on the parent fires from its child
+        assert_eq!(vec!["next", "power"], fsm.available_transitions());
+        assert!(fsm.on_event("power", None).is_ok());
+        assert_eq!(Light::Off, fsm.get_current());
+        assert_eq!(
+            vec!["leave green", "leave on", "power off", "enter off"],
+            *log.lock().unwrap()
+        );
+        assert_eq!(Ok(PruneReport::default()), fsm.prune_unreachable());
+    }
+
+    #[test]
+    fn test_fsm_deferrable_event() {
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::After(EventTag::Close),
+            Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                assert_eq!(Some(&vec![7]), e.args);
+                Ok(())
+            }),
+        )]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        assert_eq!(
+            Err(FSMError::InvalidEvent(
+                "close".to_string(),
+                "closed".to_string()
+            )),
+            fsm.on_event(EventTag::Close, Some(&vec![7]))
+        );
+
+        assert!(fsm
+            .set_deferrable(StateTag::Closed, EventTag::Close)
+            .is_ok());
+        assert_eq!(
+            Err(FSMError::Queued("close".to_string())),
+            fsm.on_event(EventTag::Close, Some(&vec![7]))
+        );
+        assert_eq!(1, fsm.get_queued_count());
+
+        // the queued close is replayed right after the FSM opens
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(0, fsm.get_queued_count());
+    }
+
+    #[test]
+    fn test_fsm_external_self_transition() {
+        let left = AtomicU32::new(0);
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Leave(StateTag::Opened),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    left.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::Enter(StateTag::Opened),
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    e.hold(Arc::new(()));
+                    Ok(())
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            [
+                (EventTag::Open, StateTag::Closed, StateTag::Opened),
+                (EventTag::Open, StateTag::Opened, StateTag::Opened),
+            ],
+            callbacks,
+        );
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(1, fsm.get_resource_count());
+
+        // internal by default
+        assert_eq!(
+            Err(FSMError::NoTransition),
+            fsm.on_event(EventTag::Open, None)
+        );
+        assert_eq!(0, left.load(Ordering::Relaxed));
+
+        assert!(fsm.set_external(EventTag::Open).is_ok());
+        assert_eq!(
+            Ok(EventRecord {
+                event: "open".to_string(),
+                src: StateTag::Opened,
+                dst: StateTag::Opened,
+            }),
+            fsm.on_event(EventTag::Open, None)
+        );
+        assert_eq!(1, left.load(Ordering::Relaxed));
+        assert_eq!(1, fsm.get_resource_count());
+    }
+
+    #[test]
+    fn test_fsm_no_transition_policy() {
+        let left = AtomicU32::new(0);
+        let after = AtomicU32::new(0);
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Leave(StateTag::Opened),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    left.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::After(EventTag::Open),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    after.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Opened,
+            [(EventTag::Open, StateTag::Opened, StateTag::Opened)],
+            callbacks,
+        );
+        let record = EventRecord {
+            event: "open".to_string(),
+            src: StateTag::Opened,
+            dst: StateTag::Opened,
+        };
+
+        assert_eq!(
+            Err(FSMError::NoTransition),
+            fsm.on_event(EventTag::Open, None)
+        );
+        assert_eq!(
+            (0, 1),
+            (left.load(Ordering::Relaxed), after.load(Ordering::Relaxed))
+        );
+
+        assert!(fsm
+            .set_no_transition_policy(NoTransitionPolicy::Ignore)
+            .is_ok());
+        assert_eq!(Ok(record.clone()), fsm.on_event(EventTag::Open, None));
+        assert_eq!(
+            (0, 2),
+            (left.load(Ordering::Relaxed), after.load(Ordering::Relaxed))
+        );
+
+        assert!(fsm
+            .set_no_transition_policy(NoTransitionPolicy::Reenter)
+            .is_ok());
+        assert_eq!(Ok(record), fsm.on_event(EventTag::Open, None));
+        assert_eq!(
+            (1, 3),
+            (left.load(Ordering::Relaxed), after.load(Ordering::Relaxed))
+        );
+    }
+
+    #[test]
+    fn test_fsm_profiling() {
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Before(EventTag::Open),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    thread::sleep(Duration::from_millis(2));
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::EnterState,
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> { Ok(()) }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!("", fsm.get_profile());
+
+        assert!(fsm.set_profiling(true).is_ok());
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        let profile = fsm.get_profile();
+        let stacks: Vec<(&str, u128)> = profile
+            .lines()
+            .map(|line| {
+                let (stack, micros) = line.rsplit_once(' ').unwrap();
+                (stack, micros.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "close;enter_state",
+                "open;before_event(open)",
+                "open;enter_state"
+            ],
+            stacks.iter().map(|(stack, _)| *stack).collect::<Vec<_>>()
+        );
+        assert!(stacks[1].1 >= 2000);
+
+        assert!(fsm.set_profiling(false).is_ok());
+        assert_eq!("", fsm.get_profile());
+    }
+
+    #[test]
+    fn test_fsm_new_at() {
+        let events = door();
+        let fsm: Result<FSMWithVec, _> = FSM::new_at(
+            StateTag::Closed,
+            [StateTag::Closed],
+            events.clone(),
+            HashMap::new(),
+        );
+        assert_eq!(StateTag::Closed, fsm.unwrap().get_current());
+
+        let fsm: Result<FSMWithVec, _> =
+            FSM::new_at(StateTag::Opened, [StateTag::Closed], events, HashMap::new());
+        assert_eq!(
+            Some(FSMError::InvalidEntryPoint("opened".to_string())),
+            fsm.err()
+        );
+    }
+
+    #[test]
+    fn test_fsm_start() {
+        let counter = AtomicU32::new(0);
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Enter(StateTag::Closed),
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    assert_eq!("", e.event);
+                    assert!(e.is_self_transition());
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::LeaveState,
+                Closure::new(|_e| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("leave state fail"))
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            [(EventTag::Open, StateTag::Closed, StateTag::Opened)],
+            callbacks,
+        );
+        assert!(fsm.start().is_ok());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+        assert_eq!(StateTag::Closed, fsm.get_current());
+    }
+
+    #[test]
+    fn test_fsm_hold_resource() {
+        struct Connection(Arc<AtomicU32>);
+        impl Drop for Connection {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let closed = Arc::new(AtomicU32::new(0));
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Enter(StateTag::Opened),
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    e.hold(Connection(closed.clone()));
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::Enter(StateTag::Closed),
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    match e.args {
+                        Some(_) => Err(MyError::CustomeError("jammed")),
+                        None => Ok(()),
+                    }
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        assert!(fsm
+            .set_hook_policy(
+                HookPolicy::default().with(CallbackType::EnterState, FailurePolicy::Rollback)
+            )
+            .is_ok());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(1, fsm.get_resource_count());
+        assert_eq!(0, closed.load(Ordering::Relaxed));
+
+        // a rolled back transition keeps the resources of its source
+        assert!(fsm.on_event(EventTag::Close, Some(&vec![1])).is_err());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(1, fsm.get_resource_count());
+        assert_eq!(0, closed.load(Ordering::Relaxed));
+
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        assert_eq!(0, fsm.get_resource_count());
+        assert_eq!(1, closed.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_fsm_final_state() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        assert!(fsm.set_final(StateTag::Opened).is_ok());
+        assert!(!fsm.is_finished());
+
+        assert_eq!(
+            Ok(EventRecord {
+                event: "open".to_string(),
+                src: StateTag::Closed,
+                dst: StateTag::Opened,
+            }),
+            fsm.on_event(EventTag::Open, None)
+        );
+        assert!(fsm.is_finished());
+        assert_eq!(
+            Err(FSMError::MachineFinished("opened".to_string())),
+            fsm.on_event(EventTag::Close, None)
+        );
+        assert_eq!(StateTag::Opened, fsm.get_current());
+
+        fsm.set_state(StateTag::Closed);
+        assert!(!fsm.is_finished());
+    }
+
+    #[test]
+    fn test_fsm_timers() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        fsm.fire_after(EventTag::Close, Duration::from_secs(10), None);
+        fsm.fire_after(EventTag::Open, Duration::from_secs(5), None);
+        fsm.fire_after(EventTag::Open, Duration::from_secs(30), None);
+
+        assert_eq!(Ok(0), fsm.tick(Duration::from_secs(4)));
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(Ok(2), fsm.tick(Duration::from_secs(6)));
+        assert_eq!(StateTag::Closed, fsm.get_current());
+
+        assert_eq!(1, fsm.cancel_timers(EventTag::Open));
+        assert_eq!(Ok(0), fsm.tick(Duration::from_secs(60)));
+
+        // a failing timer does not hold back the others, and is kept
+        fsm.fire_after(EventTag::Close, Duration::from_secs(1), None);
+        fsm.fire_after(EventTag::Open, Duration::from_secs(2), None);
+        assert_eq!(
+            Err(FSMError::InvalidEvent(
+                "close".to_string(),
+                "closed".to_string()
+            )),
+            fsm.tick(Duration::from_secs(2))
+        );
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(Ok(1), fsm.tick(Duration::ZERO));
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(Ok(0), fsm.tick(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_fsm_post() {
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::After(EventTag::Open),
+            Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                e.post(EventTag::Close, Some(vec![1]));
+                Ok(())
+            }),
+        )]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        fsm.post(EventTag::Open, None);
+        assert_eq!(1, fsm.get_posted_count());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+
+        // the close posted by the callback runs after the open completed
+        assert_eq!(Ok(2), fsm.dispatch());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(0, fsm.get_posted_count());
+
+        // the failing close stays queued, ahead of the open
+        fsm.post(EventTag::Close, None);
+        fsm.post(EventTag::Open, None);
+        assert!(fsm.dispatch().is_err());
+        assert_eq!(2, fsm.get_posted_count());
+        assert!(fsm.dispatch().is_err());
+        assert_eq!(Some("close".to_string()), fsm.skip_posted());
+        assert_eq!(Ok(2), fsm.dispatch());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+    }
+
+    #[test]
+    fn test_fsm_dispatch_deferred() {
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::Leave(StateTag::Closed),
+            Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                e.defer();
+                Ok(())
+            }),
+        )]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        fsm.post(EventTag::Open, None);
+        assert_eq!(Ok(1), fsm.dispatch());
+        assert_eq!(0, fsm.get_posted_count());
+        assert!(fsm.is_in_transition());
+
+        // the deferred open is not fired again once it completes
+        assert!(fsm.transition().is_ok());
+        assert_eq!(Ok(0), fsm.dispatch());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+    }
+
+    #[test]
+    fn test_fsm_dispatch_queued() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        assert!(fsm
+            .set_deferrable(StateTag::Closed, EventTag::Close)
+            .is_ok());
+        fsm.post(EventTag::Close, None);
+        assert_eq!(Ok(1), fsm.dispatch());
+        assert_eq!(Ok(0), fsm.dispatch());
+        assert_eq!(0, fsm.get_posted_count());
+        assert_eq!(1, fsm.get_queued_count());
+
+        // the queued close is replayed once, right after the FSM opens
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(0, fsm.get_queued_count());
+        assert_eq!(0, fsm.get_posted_count());
+    }
+
+    #[test]
+    fn test_fsm_typed_args() {
+        #[derive(Debug, Clone, PartialEq)]
+        enum Command {
+            Open { by: &'static str },
+            Close,
+        }
+
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::Enter(StateTag::Opened),
+            Closure::new(|e: &Event<StateTag, Command>| -> Result<(), MyError> {
+                match e.args {
+                    Some(Command::Open { by }) => assert_eq!("alice", *by),
+                    _ => panic!("unexpected args {:?}", e.args),
+                }
+                Ok(())
+            }),
+        )]);
+        let mut fsm: FSM<_, Command, _> = FSM::new(StateTag::Closed, door(), callbacks);
+        assert!(fsm
+            .on_event(EventTag::Open, Some(&Command::Open { by: "alice" }))
+            .is_ok());
+        assert!(fsm.on_event(EventTag::Close, Some(&Command::Close)).is_ok());
+    }
+
+    #[test]
+    fn test_fsm_history() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(0, fsm.history().count());
+
+        assert!(fsm.set_history_capacity(2).is_ok());
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(Ok(0), fsm.tick(Duration::from_secs(5)));
+        assert!(fsm.on_event(EventTag::Open, None).is_err());
+        let history: Vec<_> = fsm.history().collect();
+        assert_eq!(2, history.len());
+        assert_eq!(
+            EventRecord {
+                event: "open".to_string(),
+                src: StateTag::Closed,
+                dst: StateTag::Opened,
+            },
+            history[0].record
+        );
+        assert_eq!(None, history[0].error);
+        assert_eq!(StateTag::Opened, history[1].record.dst);
+        assert_eq!(
+            Some("event open inappropriate in current state opened".to_string()),
+            history[1].error
+        );
+        assert_eq!(Duration::ZERO, history[0].at);
+        assert_eq!(Duration::from_secs(5), history[1].at);
+        assert!(history[0].time > SystemTime::UNIX_EPOCH);
+        assert!(history[0].time <= history[1].time);
+
+        assert!(fsm.set_history_capacity(1).is_ok());
+        assert_eq!(1, fsm.history().count());
+    }
+
+    #[test]
+    fn test_fsm_force_event() {
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::Before(EventTag::Close),
+            Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                Err(MyError::CustomeError("stuck"))
+            }),
+        )]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Opened, door(), callbacks);
+        assert!(fsm.on_event(EventTag::Close, None).is_err());
+        assert_eq!(
+            Err(FSMError::InvalidEvent(
+                "close".to_string(),
+                "opened".to_string()
+            )),
+            fsm.force_event(EventTag::Close, StateTag::Opened, None)
+        );
+
+        let record = EventRecord {
+            event: "close".to_string(),
+            src: StateTag::Opened,
+            dst: StateTag::Closed,
+        };
+        assert_eq!(
+            Ok(record.clone()),
+            fsm.force_event(EventTag::Close, StateTag::Closed, None)
+        );
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(vec![&record], fsm.get_forced().collect::<Vec<_>>());
+
+        // only the last forced transitions are kept
+        assert!(fsm.set_forced_capacity(1).is_ok());
+        assert!(fsm
+            .force_event(EventTag::Open, StateTag::Opened, None)
+            .is_ok());
+        assert_eq!(1, fsm.get_forced().count());
+        assert_eq!(
+            Some("open"),
+            fsm.get_forced().last().map(|r| r.event.as_str())
+        );
+
+        // a forced transition whose callback fails is still recorded
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Opened,
+            door(),
+            HashMap::from([(
+                HookType::Transition(EventTag::Close, StateTag::Opened),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("stuck"))
+                }),
+            )]),
+        );
+        assert!(fsm.set_history_capacity(1).is_ok());
+        assert!(fsm
+            .force_event(EventTag::Close, StateTag::Closed, None)
+            .is_err());
+        assert_eq!(vec![&record], fsm.get_forced().collect::<Vec<_>>());
+        let entry = fsm.history().next().unwrap();
+        assert_eq!(StateTag::Opened, entry.record.dst);
+        assert!(entry.error.is_some());
+    }
+
+    #[test]
+    fn test_fsm_freeze_hooks() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        assert!(fsm.set_external(EventTag::Close).is_ok());
+        fsm.freeze_hooks();
+        assert!(fsm.is_frozen());
+
+        assert_eq!(Err(FSMError::Frozen), fsm.set_final(StateTag::Opened));
+        assert_eq!(
+            Err(FSMError::Frozen),
+            fsm.set_chooser(EventTag::Open, |_, _| StateTag::Closed)
+        );
+        assert_eq!(
+            Err(FSMError::Frozen),
+            fsm.set_parent(StateTag::Opened, StateTag::Closed)
+        );
+        assert_eq!(
+            Err(FSMError::Frozen),
+            fsm.set_hook_policy(HookPolicy::default())
+        );
+        assert_eq!(
+            Err(FSMError::Frozen),
+            fsm.set_fallback(|_, _| Fallback::Ignore)
+        );
+        assert_eq!(Err(FSMError::Frozen), fsm.set_poison_mode(true));
+        assert_eq!(Err(FSMError::Frozen), fsm.set_history_capacity(8));
+        assert_eq!(Err(FSMError::Frozen), fsm.prune_unreachable());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert!(!fsm.is_finished());
+        assert!(fsm.on_event("jump", None).is_err());
+        assert!(fsm.clone().is_frozen());
+    }
+
+    #[test]
+    fn test_fsm_fallback() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        assert!(fsm
+            .set_fallback(|state, event| match (state, event) {
+                (StateTag::Closed, "close") => Fallback::Ignore,
+                (StateTag::Closed, "toggle") => Fallback::Translate("open".to_string()),
+                (StateTag::Opened, "toggle") => Fallback::Translate("close".to_string()),
+                _ => Fallback::Reject,
+            })
+            .is_ok());
+
+        assert_eq!(
+            Ok(EventRecord {
+                event: "close".to_string(),
+                src: StateTag::Closed,
+                dst: StateTag::Closed,
+            }),
+            fsm.on_event(EventTag::Close, None)
+        );
+        assert_eq!("open", fsm.on_event("toggle", None).unwrap().event);
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert!(fsm.on_event("toggle", None).is_ok());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(
+            Err(FSMError::UnknownEvent("jump".to_string())),
+            fsm.on_event("jump", None)
+        );
+    }
+
+    #[test]
+    fn test_fsm_chooser() {
+        let counter = AtomicU32::new(0);
+        let callbacks = HashMap::from([(
+            HookType::<&str, StateTag>::Enter(StateTag::Opened),
+            Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }),
+        )]);
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            [(
+                "knock",
+                &[StateTag::Closed, StateTag::Opened][..],
+                StateTag::Closed,
+            )],
+            callbacks,
+        );
+        assert!(fsm
+            .set_chooser(
+                "knock",
+                |_src: &StateTag, args: Option<&Vec<u32>>| match args.map_or(0, |args| args.len()) {
+                    3 => StateTag::Opened,
+                    _ => StateTag::Closed,
+                },
+            )
+            .is_ok());
+
+        assert_eq!(Err(FSMError::NoTransition), fsm.on_event("knock", None));
+        assert!(fsm.on_event("knock", Some(&vec![1, 1, 1])).is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+        assert!(fsm.on_event("knock", Some(&vec![1])).is_ok());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+    }
+
+    #[test]
+    fn test_event_helpers() {
+        #[derive(strum::EnumString, Debug, PartialEq)]
+        #[strum(serialize_all = "snake_case")]
+        enum Door {
+            Open,
+        }
+
+        let records = Mutex::new(Vec::new());
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::BeforeEvent,
+            Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                assert!(e.is_event(EventTag::Open));
+                assert!(!e.is_event(EventTag::Close));
+                assert_eq!(Some(Door::Open), e.parse_event());
+                assert!(!e.is_self_transition());
+                records.lock().unwrap().push(e.to_record());
+                Ok(())
+            }),
+        )]);
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            vec![EventDesc {
+                name: EventTag::Open,
+                src: vec![StateTag::Closed],
+                dst: StateTag::Opened,
+            }],
+            callbacks,
+        );
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        drop(fsm);
+        assert_eq!(
+            vec![EventRecord {
+                event: "open".to_string(),
+                src: StateTag::Closed,
+                dst: StateTag::Opened,
+            }],
+            records.into_inner().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fsm_clone() {
+        #[derive(Debug)]
+        struct NotClone;
+        impl<S, I> Action<S, I> for NotClone {
+            type Err = MyError;
+            fn call(&self, _e: &Event<S, I>) -> Result<(), Self::Err> {
+                Ok(())
+            }
+        }
+
+        let fsm: FSM<_, Vec<u32>, _> = FSM::new(
+            StateTag::Closed,
+            vec![EventDesc {
+                name: EventTag::Open,
+                src: vec![StateTag::Closed],
+                dst: StateTag::Opened,
+            }],
+            vec![(HookType::BeforeEvent, NotClone)],
+        );
+        let mut dry_run = fsm.clone();
+        assert!(Arc::ptr_eq(&fsm.transitions, &dry_run.transitions));
+        assert!(Arc::ptr_eq(&fsm.callbacks, &dry_run.callbacks));
+
+        assert!(dry_run.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(StateTag::Opened, dry_run.get_current());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+    }
+
+    #[test]
+    fn test_fsm_from_tuples() {
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            door(),
+            vec![(
+                HookType::Before(EventTag::Open),
+                Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+            )],
+        );
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+
+        let sources = [StateTag::Closed, StateTag::Opened];
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Opened,
+            [("reset", &sources[..], StateTag::Closed)],
+            HashMap::new(),
+        );
+        assert!(fsm.on_event("reset", None).is_ok());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(Err(FSMError::NoTransition), fsm.on_event("reset", None));
+    }
+
+    #[test]
+    fn test_fsm_prune_unreachable() {
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Opened,
+            [
+                ("lock", StateTag::Closed, StateTag::Opened),
+                ("stay", StateTag::Opened, StateTag::Opened),
+            ],
+            HashMap::new(),
+        );
+        let report = fsm.prune_unreachable();
+        assert_eq!(
+            Ok(PruneReport {
+                states: vec!["closed".to_string()],
+                transitions: vec![("lock".to_string(), "closed".to_string())],
+            }),
+            report
+        );
+        assert_eq!(
+            "current: opened\ntransitions:\n  stay: opened -> opened\nhooks:",
+            fsm.describe()
+        );
+
+        // nothing left to prune
+        assert_eq!(Ok(PruneReport::default()), fsm.prune_unreachable());
+
+        // a reachable chooser may route anywhere, so nothing is pruned
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Opened,
+            [
+                ("lock", StateTag::Closed, StateTag::Opened),
+                ("stay", StateTag::Opened, StateTag::Opened),
+            ],
+            HashMap::new(),
+        );
+        assert!(fsm.set_chooser("stay", |_, _| StateTag::Closed).is_ok());
+        assert_eq!(Ok(PruneReport::default()), fsm.prune_unreachable());
+        assert!(fsm.on_event("stay", None).is_ok());
+        assert!(fsm.on_event("lock", None).is_ok());
+    }
+
+    #[test]
+    fn test_fsm_checkpoint() {
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), HashMap::new());
+        assert_eq!(Err(FSMError::NoCheckpoint(0)), fsm.restore_checkpoint(0));
+        assert_eq!(
+            Err(FSMError::NoCheckpoint(usize::MAX)),
+            fsm.restore_checkpoint(usize::MAX)
+        );
+
+        assert!(fsm.set_checkpoint_capacity(2).is_ok());
+        fsm.checkpoint();
+        assert!(fsm.on_event(Sequenced(1, EventTag::Open), None).is_ok());
+        fsm.checkpoint();
+        assert!(fsm.on_event(Sequenced(2, EventTag::Close), None).is_ok());
+        fsm.checkpoint();
+
+        // the first checkpoint was dropped
+        assert_eq!(Err(FSMError::NoCheckpoint(2)), fsm.restore_checkpoint(2));
+
+        assert!(fsm.restore_checkpoint(1).is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(Some(1), fsm.get_sequence());
+
+        assert!(fsm.restore_checkpoint(0).is_ok());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(Some(2), fsm.get_sequence());
+    }
+
+    #[test]
+    fn test_fsm_checkpoint_queued_and_context() {
+        let mut fsm: FSM<_, Vec<u32>, Closure<_, _, MyError, u32>, _> =
+            FSM::new_with_context(StateTag::Closed, door(), HashMap::new(), 0);
+        assert!(fsm
+            .set_deferrable(StateTag::Closed, EventTag::Close)
+            .is_ok());
+        fsm.checkpoint();
+
+        // queued events are restored
+        assert!(fsm.on_event(EventTag::Close, None).is_err());
+        assert_eq!(1, fsm.get_queued_count());
+        assert!(fsm.restore_checkpoint(0).is_ok());
+        assert_eq!(0, fsm.get_queued_count());
+
+        // the context must be restored first
+        fsm.set_context(7);
+        assert_eq!(Err(FSMError::ContextMismatch(0)), fsm.restore_checkpoint(0));
+        fsm.set_context(0);
+        assert!(fsm.restore_checkpoint(0).is_ok());
+    }
+
+    #[test]
+    fn test_fsm_sandbox() {
+        let counter = AtomicU32::new(0);
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            door(),
+            vec![
+                (
+                    HookType::EnterState,
+                    Closure::new(|_e| -> Result<(), MyError> {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        Ok(())
+                    }),
+                ),
+                (
+                    HookType::Before(EventTag::Close),
+                    Closure::new(|e| -> Result<(), MyError> {
+                        match e.args {
+                            Some(_) => Err(MyError::CustomeError("jammed")),
+                            None => Ok(()),
+                        }
+                    }),
+                ),
+            ],
+        );
+        assert!(fsm.set_history_capacity(4).is_ok());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+
+        let mut sandbox = fsm.sandbox();
+        assert!(sandbox.is_sandboxed());
+        assert_eq!(0, sandbox.history().count());
+
+        // guards still run in the sandbox
+        assert!(sandbox.on_event(EventTag::Close, Some(&vec![1])).is_err());
+        assert_eq!(StateTag::Opened, sandbox.get_current());
+        assert!(sandbox.on_event(EventTag::Close, None).is_ok());
+        assert!(sandbox.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(1, fsm.history().count());
+
+        assert!(!fsm.is_sandboxed());
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        assert_eq!(2, counter.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_fsm_sandbox_side_effects() {
+        let sent = AtomicU32::new(0);
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::Before(EventTag::Open),
+            Closure::new(
+                |e: &Event<StateTag, Vec<u32>, u32>| -> Result<(), MyError> {
+                    e.once("notify", || {
+                        sent.fetch_add(1, Ordering::Relaxed);
+                    });
+                    *e.get_context() += 1;
+                    e.set_metadata("opened", true);
+                    Ok(())
+                },
+            ),
+        )]);
+        let mut fsm: FSM<_, Vec<u32>, Closure<_, _, MyError, u32>, _> =
+            FSM::new_with_context(StateTag::Closed, door(), callbacks, 0);
+
+        let mut sandbox = fsm.sandbox();
+        assert!(sandbox.on_event(Sequenced(1, EventTag::Open), None).is_ok());
+        assert_eq!(0, sent.load(Ordering::Relaxed));
+        assert_eq!(1, *sandbox.get_context());
+        assert_eq!(Some(Arc::new(true)), sandbox.get_metadata("opened"));
+
+        // the sandbox left no trace on the FSM
+        assert_eq!(0, *fsm.get_context());
+        assert_eq!(None, fsm.get_metadata::<bool>("opened"));
+        assert!(fsm.get_effects().is_empty());
+
+        assert!(fsm.on_event(Sequenced(1, EventTag::Open), None).is_ok());
+        assert_eq!(1, sent.load(Ordering::Relaxed));
+        assert_eq!(1, *fsm.get_context());
+    }
+
+    #[test]
+    fn test_fsm_wildcard_src() {
+        let counter = AtomicU32::new(0);
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            vec![
+                EventDesc {
+                    name: "open",
+                    src: vec![StateTag::Closed],
+                    dst: StateTag::Opened,
+                },
+                EventDesc {
+                    name: "reset",
+                    src: vec![],
+                    dst: StateTag::Closed,
+                },
+                EventDesc {
+                    name: "reset",
+                    src: vec![StateTag::Closed],
+                    dst: StateTag::Opened,
+                },
+            ],
+            HashMap::from([(
+                HookType::TransitionFromAny("reset"),
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    assert_eq!(StateTag::Opened, *e.src);
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            )]),
+        );
+        assert_eq!(
+            vec!["open:closed", "reset:closed"],
+            fsm.missing_hooks(CallbackType::Transition)
+        );
+        assert!(fsm.can("reset"));
+        assert_eq!(vec!["open", "reset"], fsm.available_transitions());
+        assert_eq!(
+            vec![
+                ("open", "closed", &StateTag::Opened),
+                ("reset", "", &StateTag::Closed),
+                ("reset", "closed", &StateTag::Opened),
+            ],
+            fsm.transitions().collect::<Vec<_>>()
+        );
+
+        // an exact source wins over the wildcard
+        assert!(fsm.on_event("reset", None).is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+
+        assert!(fsm.can("reset"));
+        assert_eq!(vec!["reset"], fsm.available_transitions());
+        assert!(fsm.on_event("reset", None).is_ok());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+
+        assert!(fsm.describe().contains("reset: * -> closed"));
+        assert_eq!(Ok(PruneReport::default()), fsm.prune_unreachable());
+        assert!(fsm.missing_hooks(CallbackType::EnterState).len() == 2);
+    }
+
+    #[test]
+    fn test_fsm_before_event_fail() {
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::BeforeEvent,
+                Closure::new(|_e| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("before event fail"))
+                }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::AfterEvent,
+                Closure::new(|_e| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("after event fail"))
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithHashMap = FSM::new(
+            StateTag::Closed,
+            vec![
+                EventDesc {
+                    name: EventTag::Open,
+                    src: vec![StateTag::Closed],
+                    dst: StateTag::Opened,
+                },
+                EventDesc {
+                    name: EventTag::Close,
+                    src: vec![StateTag::Opened],
+                    dst: StateTag::Closed,
+                },
+            ],
+            callbacks,
+        );
+        assert_eq!(StateTag::Closed, fsm.get_current());
+
+        let ret = fsm.on_event("open", None);
+        assert!(ret.is_err());
+        assert_eq!(
+            ret.err().unwrap(),
+            FSMError::InternalError("my error: before event fail".to_string())
+        );
+        assert_eq!(StateTag::Closed, fsm.get_current());
+    }
+
+    #[test]
+    fn test_fsm_leave_state_fail() {
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::LeaveState,
+            Closure::new(|_e| -> Result<(), MyError> {
+                Err(MyError::CustomeError("leave state fail"))
+            }),
+        )]);
+        let mut fsm: FSMWithHashMap = FSM::new(
+            StateTag::Closed,
+            vec![
+                EventDesc {
+                    name: EventTag::Open,
+                    src: vec![StateTag::Closed],
+                    dst: StateTag::Opened,
+                },
+                EventDesc {
+                    name: EventTag::Close,
+                    src: vec![StateTag::Opened],
+                    dst: StateTag::Closed,
+                },
+            ],
+            callbacks,
+        );
+        assert_eq!(StateTag::Closed, fsm.get_current());
+
+        let ret = fsm.on_event("open", None);
+        assert!(ret.is_err());
+        assert_eq!(
+            ret.err().unwrap(),
+            FSMError::InternalError("my error: leave state fail".to_string())
+        );
+        assert_eq!(StateTag::Closed, fsm.get_current());
+    }
+
+    #[test]
+    fn test_fsm_hook_trace() {
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Before(EventTag::Open),
+                Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::BeforeEvent,
+                Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::Leave(StateTag::Closed),
+                Closure::new(|_e| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("leave state fail"))
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithHashMap = FSM::new(
+            StateTag::Closed,
+            vec![EventDesc {
+                name: EventTag::Open,
+                src: vec![StateTag::Closed],
+                dst: StateTag::Opened,
+            }],
+            callbacks,
+        );
+
+        let err = fsm.on_event("open", None).err().unwrap();
+        assert_eq!(
+            err,
+            FSMError::WithTrace(
+                vec!["before_event(open)".to_string(), "before_event".to_string()],
+                Box::new(FSMError::InternalError(
+                    "my error: leave state fail".to_string()
+                ))
+            )
+        );
+        assert_eq!(
+            "internal error: my error: leave state fail \
+             (after hooks: before_event(open), before_event)",
+            err.to_string()
+        );
+        assert_eq!(StateTag::Closed, fsm.get_current());
+    }
+
+    #[test]
+    fn test_fsm_poison_on_dirty() {
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::Leave(StateTag::Opened),
+            Closure::new(
+                |e: &Event<StateTag, HashMap<u32, u32>>| -> Result<(), MyError> {
+                    e.mark_dirty();
+                    Err(MyError::CustomeError("leave state fail"))
+                },
+            ),
+        )]);
+        let events = || door();
+
+        // poison mode is disabled by default
+        let mut fsm: FSMWithHashMap = FSM::new(StateTag::Opened, events(), callbacks.clone());
+        assert!(fsm.on_event("close", None).is_err());
+        assert!(!fsm.is_poisoned());
+
+        let mut fsm: FSMWithHashMap = FSM::new(StateTag::Opened, events(), callbacks);
+        assert!(fsm.set_poison_mode(true).is_ok());
+        assert!(fsm.on_event("close", None).is_err());
+        assert!(fsm.is_poisoned());
+        assert_eq!(
+            fsm.on_event("close", None).err().unwrap(),
+            FSMError::Poisoned
+        );
+
+        fsm.repair();
+        assert!(!fsm.is_poisoned());
+        assert!(fsm.can(EventTag::Close));
+    }
+
+    #[test]
+    fn test_fsm_hook_policy() {
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::BeforeEvent,
+                Closure::new(|_e| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("before event fail"))
+                }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::EnterState,
+                Closure::new(|_e| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("enter state fail"))
+                }),
+            ),
+        ]);
+        let events = || door();
+
+        // ignore before, rollback on enter
+        let mut fsm: FSMWithHashMap = FSM::new(StateTag::Closed, events(), callbacks.clone());
+        assert!(fsm
+            .set_hook_policy(
+                HookPolicy::default()
+                    .with(CallbackType::BeforeEvent, FailurePolicy::Ignore)
+                    .with(CallbackType::EnterState, FailurePolicy::Rollback),
+            )
+            .is_ok());
+        assert_eq!(
+            fsm.on_event(Sequenced(1, "open"), None).err().unwrap(),
+            FSMError::InternalError("my error: enter state fail".to_string())
+        );
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(None, fsm.get_sequence());
+        assert_eq!(Err(FSMError::NoTransition), fsm.rollback());
+
+        // ignore before, abort on enter keeps the new state
+        assert!(fsm
+            .set_hook_policy(
+                HookPolicy::default()
+                    .with(CallbackType::BeforeEvent, FailurePolicy::Ignore)
+                    .with(CallbackType::EnterState, FailurePolicy::Abort),
+            )
+            .is_ok());
+        assert!(fsm.on_event("open", None).is_err());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+
+        // poison on before
+        let mut fsm: FSMWithHashMap = FSM::new(StateTag::Closed, events(), callbacks);
+        assert!(fsm
+            .set_hook_policy(
+                HookPolicy::default().with(CallbackType::BeforeEvent, FailurePolicy::Poison),
+            )
+            .is_ok());
+        assert!(fsm.on_event("open", None).is_err());
+        assert!(fsm.is_poisoned());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+    }
+
+    #[test]
+    fn test_fsm_require_hooks() {
+        let fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            door(),
+            vec![
+                (
+                    HookType::EnterState,
+                    Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+                ),
+                (
+                    HookType::Enter(StateTag::Opened),
+                    Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+                ),
+                (
+                    HookType::Before(EventTag::Open),
+                    Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+                ),
+                (
+                    HookType::Before(EventTag::Close),
+                    Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+                ),
+            ],
+        );
+        assert_eq!(
+            vec!["closed".to_string()],
+            fsm.missing_hooks(CallbackType::EnterState)
+        );
+        assert!(fsm.missing_hooks(CallbackType::BeforeEvent).is_empty());
+        assert_eq!(
+            vec!["close".to_string(), "open".to_string()],
+            fsm.missing_hooks(CallbackType::AfterEvent)
+        );
+
+        let fsm = fsm.require_hooks(CallbackType::BeforeEvent).unwrap();
+        let err = fsm.require_hooks(CallbackType::EnterState).err().unwrap();
+        assert_eq!(
+            err,
+            FSMError::MissingHooks(vec!["enter_state(closed)".to_string()])
+        );
+        assert_eq!("missing hooks: enter_state(closed)", err.to_string());
+    }
+
+    #[test]
+    fn test_fsm_cancel() {
+        let counter = AtomicU32::new(0);
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Leave(StateTag::Closed),
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    if e.args.is_none() {
+                        e.cancel("no args");
+                    }
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::LeaveState,
+                Closure::new(|_e| -> Result<(), MyError> {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            [(EventTag::Open, StateTag::Closed, StateTag::Opened)],
+            callbacks,
+        );
+
+        let err = fsm.on_event(EventTag::Open, None).err().unwrap();
+        assert_eq!(err, FSMError::Canceled("no args".to_string()));
+        assert_eq!("transition canceled: no args", err.to_string());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(0, counter.load(Ordering::Relaxed));
+
+        assert!(fsm.on_event(EventTag::Open, Some(&vec![1])).is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_fsm_defer() {
+        let entered = AtomicU32::new(0);
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Leave(StateTag::Closed),
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    e.defer();
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::Enter(StateTag::Opened),
+                Closure::new(|_e| -> Result<(), MyError> {
+                    entered.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        assert_eq!(Err(FSMError::NotInTransition), fsm.transition());
+
+        assert_eq!(
+            Err(FSMError::Deferred("open".to_string())),
+            fsm.on_event(EventTag::Open, None)
+        );
+        assert!(fsm.is_in_transition());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(0, entered.load(Ordering::Relaxed));
+        assert_eq!(
+            Err(FSMError::InTransition("close".to_string())),
+            fsm.on_event(EventTag::Close, None)
+        );
+
+        assert!(fsm.transition().is_ok());
+        assert!(!fsm.is_in_transition());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(1, entered.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_fsm_rollback() {
+        let counter = AtomicU32::new(0);
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::Enter(StateTag::Closed),
+            Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                assert_eq!(StateTag::Opened, *e.src);
+                counter.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }),
+        )]);
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            [(EventTag::Open, StateTag::Closed, StateTag::Opened)],
+            callbacks,
+        );
+        assert_eq!(Err(FSMError::NoTransition), fsm.rollback());
+
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert!(fsm.rollback().is_ok());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+        assert_eq!(Err(FSMError::NoTransition), fsm.rollback());
+    }
+
+    #[test]
+    fn test_fsm_transact() {
+        let counter = AtomicU32::new(0);
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Enter(StateTag::Opened),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::Before(EventTag::Close),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("locked"))
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        assert!(fsm.set_history_capacity(8).is_ok());
 
-            assert!(fsm.on_event("open", Some(&Vec::new())).is_ok());
-            assert_eq!(StateTag::Opened, fsm.get_current());
+        // rejected by the transition table
+        let ret = fsm.transact(|tx| {
+            tx.on_event(EventTag::Open, None)?;
+            tx.on_event(EventTag::Open, None)
+        });
+        assert!(ret.is_err());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(0, fsm.history().count());
 
-            assert!(fsm.on_event("close", Some(&Vec::new())).is_ok());
-            assert_eq!(StateTag::Closed, fsm.get_current());
+        // rejected by a guard: the Enter callback of the first step is
+        // buffered, so it never runs
+        let ret = fsm.transact(|tx| {
+            tx.on_event(EventTag::Open, None)?;
+            tx.on_event(EventTag::Close, None)
+        });
+        assert!(ret.is_err());
+        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(0, counter.load(Ordering::Relaxed));
+        assert_eq!(0, fsm.history().count());
 
-            let ret = fsm.on_event("close", None);
-            assert!(ret.is_err());
-            assert_eq!(
-                ret.err().unwrap(),
-                FSMError::InvalidEvent("close".to_string(), "closed".to_string())
-            );
-            assert_eq!(StateTag::Closed, fsm.get_current());
-        }
+        let ret = fsm.transact(|tx| tx.on_event(EventTag::Open, None));
+        assert!(ret.is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+        assert_eq!(1, fsm.history().count());
+    }
 
-        {
-            let mut fsm: FSMWithVec = FSM::new(
-                StateTag::Closed,
-                vec![
-                    EventDesc {
-                        name: EventTag::Open,
-                        src: vec![StateTag::Closed],
-                        dst: StateTag::Opened,
-                    },
-                    EventDesc {
-                        name: EventTag::Close,
-                        src: vec![StateTag::Opened],
-                        dst: StateTag::Closed,
-                    },
-                ],
-                vec![(
-                    HookType::<EventTag, StateTag>::BeforeEvent,
-                    Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
-                )],
-            );
-            assert_eq!(StateTag::Closed, fsm.get_current());
+    #[test]
+    fn test_fsm_transact_commit() {
+        let counter = AtomicU32::new(0);
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Enter(StateTag::Opened),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::Enter(StateTag::Closed),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("jammed"))
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        assert!(fsm.set_history_capacity(8).is_ok());
 
-            assert!(fsm.on_event("open", Some(&Vec::new())).is_ok());
-            assert_eq!(StateTag::Opened, fsm.get_current());
+        // a failing nested transaction only rolls back its own steps
+        let ret = fsm.transact(|tx| {
+            tx.on_event(EventTag::Open, None)?;
+            let nested = tx.transact(|tx| {
+                tx.on_event(EventTag::Close, None)?;
+                tx.on_event(EventTag::Close, None)
+            });
+            assert!(nested.is_err());
+            assert_eq!(StateTag::Opened, tx.get_current());
+            assert_eq!(0, counter.load(Ordering::Relaxed));
+            Ok(())
+        });
+        assert!(ret.is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+        assert_eq!(1, fsm.history().count());
 
-            assert!(fsm.on_event("close", Some(&Vec::new())).is_ok());
-            assert_eq!(StateTag::Closed, fsm.get_current());
+        // a buffered callback failing on commit rolls the transaction back,
+        // unless the hook policy ignores it
+        assert!(fsm
+            .set_hook_policy(
+                HookPolicy::default().with(CallbackType::EnterState, FailurePolicy::Abort)
+            )
+            .is_ok());
+        let ret = fsm.transact(|tx| {
+            tx.on_event(EventTag::Close, None)?;
+            tx.on_event(EventTag::Open, None)
+        });
+        assert_eq!(
+            ret.err().unwrap(),
+            FSMError::InternalError("my error: jammed".to_string())
+        );
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(1, counter.load(Ordering::Relaxed));
+        assert_eq!(1, fsm.history().count());
 
-            let ret = fsm.on_event("close", None);
-            assert!(ret.is_err());
-            assert_eq!(
-                ret.err().unwrap(),
-                FSMError::InvalidEvent("close".to_string(), "closed".to_string())
-            );
-            assert_eq!(StateTag::Closed, fsm.get_current());
-        }
+        assert!(fsm.set_hook_policy(HookPolicy::default()).is_ok());
+        let ret = fsm.transact(|tx| {
+            tx.on_event(EventTag::Close, None)?;
+            tx.on_event(EventTag::Open, None)
+        });
+        assert!(ret.is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(2, counter.load(Ordering::Relaxed));
+        assert_eq!(3, fsm.history().count());
     }
 
     #[test]
-    fn test_fsm_before_event_fail() {
+    fn test_fsm_set_state() {
+        let counter = AtomicU32::new(0);
         let callbacks = HashMap::from([
             (
-                HookType::<EventTag, StateTag>::BeforeEvent,
+                HookType::<EventTag, StateTag>::Leave(StateTag::Closed),
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    assert_eq!("", e.event);
+                    assert_eq!(0, counter.fetch_add(1, Ordering::Relaxed));
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::Enter(StateTag::Opened),
                 Closure::new(|_e| -> Result<(), MyError> {
-                    Err(MyError::CustomeError("before event fail"))
+                    assert_eq!(1, counter.fetch_add(1, Ordering::Relaxed));
+                    Ok(())
                 }),
             ),
             (
-                HookType::<EventTag, StateTag>::AfterEvent,
+                HookType::<EventTag, StateTag>::BeforeEvent,
                 Closure::new(|_e| -> Result<(), MyError> {
-                    Err(MyError::CustomeError("after event fail"))
+                    Err(MyError::CustomeError("before event fail"))
                 }),
             ),
         ]);
-        let mut fsm: FSMWithHashMap = FSM::new(
+        let mut fsm: FSMWithVec = FSM::new(
             StateTag::Closed,
-            vec![
-                EventDesc {
-                    name: EventTag::Open,
-                    src: vec![StateTag::Closed],
-                    dst: StateTag::Opened,
-                },
-                EventDesc {
-                    name: EventTag::Close,
-                    src: vec![StateTag::Opened],
-                    dst: StateTag::Closed,
-                },
-            ],
+            [(EventTag::Open, StateTag::Closed, StateTag::Opened)],
             callbacks,
         );
+
+        assert!(fsm.set_state_with_hooks(StateTag::Opened).is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(2, counter.load(Ordering::Relaxed));
+
+        assert!(fsm.set_state_with_hooks(StateTag::Opened).is_ok());
+        assert_eq!(2, counter.load(Ordering::Relaxed));
+
+        fsm.set_state(StateTag::Closed);
         assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(2, counter.load(Ordering::Relaxed));
+    }
 
-        let ret = fsm.on_event("open", None);
-        assert!(ret.is_err());
+    #[test]
+    fn test_fsm_event_bytes() {
+        let mut fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            [(EventTag::Open, StateTag::Closed, StateTag::Opened)],
+            HashMap::new(),
+        );
+        let packet = b"open\x00\xff";
         assert_eq!(
-            ret.err().unwrap(),
-            FSMError::InternalError("my error: before event fail".to_string())
+            Err(FSMError::UnknownEvent("open\u{0}\u{fffd}".to_string())),
+            fsm.on_event(Bytes(packet), None)
         );
-        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert!(fsm.on_event(Bytes(&packet[..4]), None).is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
     }
 
     #[test]
-    fn test_fsm_leave_state_fail() {
-        let callbacks = HashMap::from([(
-            HookType::<EventTag, StateTag>::LeaveState,
-            Closure::new(|_e| -> Result<(), MyError> {
-                Err(MyError::CustomeError("leave state fail"))
-            }),
-        )]);
-        let mut fsm: FSMWithHashMap = FSM::new(
+    fn test_fsm_transition_action() {
+        let counter = AtomicU32::new(0);
+        let callbacks = HashMap::from([
+            (
+                HookType::<&str, StateTag>::LeaveState,
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::Transition("toggle", StateTag::Closed),
+                Closure::new(|e| -> Result<(), MyError> {
+                    assert_eq!(CallbackType::Transition, e.phase());
+                    assert_eq!(1, counter.fetch_add(10, Ordering::Relaxed));
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::Transition("toggle", StateTag::Opened),
+                Closure::new(|_e| -> Result<(), MyError> {
+                    Err(MyError::CustomeError("transition fail"))
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(
             StateTag::Closed,
-            vec![
-                EventDesc {
-                    name: EventTag::Open,
-                    src: vec![StateTag::Closed],
-                    dst: StateTag::Opened,
-                },
-                EventDesc {
-                    name: EventTag::Close,
-                    src: vec![StateTag::Opened],
-                    dst: StateTag::Closed,
-                },
+            [
+                ("toggle", StateTag::Closed, StateTag::Opened),
+                ("toggle", StateTag::Opened, StateTag::Closed),
+                ("open", StateTag::Closed, StateTag::Opened),
             ],
             callbacks,
         );
-        assert_eq!(StateTag::Closed, fsm.get_current());
+        assert_eq!(
+            vec!["open:closed"],
+            fsm.missing_hooks(CallbackType::Transition)
+        );
 
-        let ret = fsm.on_event("open", None);
-        assert!(ret.is_err());
+        assert!(fsm.on_event("toggle", None).is_ok());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+        assert_eq!(11, counter.load(Ordering::Relaxed));
+
+        let err = fsm.on_event("toggle", None).err().unwrap();
+        assert_eq!("internal_error", err.code());
+        assert_eq!(StateTag::Opened, fsm.get_current());
+    }
+
+    #[test]
+    fn test_event_data() {
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::BeforeEvent,
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    assert!(e.get_data::<u32>("sum").is_none());
+                    e.set_data("sum", e.args.map_or(0, |args| args.iter().sum::<u32>()));
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::AfterEvent,
+                Closure::new(|e| -> Result<(), MyError> {
+                    assert_eq!(Some(6), e.get_data::<u32>("sum").map(|sum| *sum));
+                    assert!(e.get_data::<String>("sum").is_none());
+                    Ok(())
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        assert!(fsm.on_event(EventTag::Open, Some(&vec![1, 2, 3])).is_ok());
+        assert!(fsm.on_event(EventTag::Close, Some(&vec![6])).is_ok());
+    }
+
+    #[test]
+    fn test_fsm_context() {
+        #[derive(Debug, Clone, Default, PartialEq)]
+        struct Door {
+            opened: u32,
+            log: Vec<String>,
+        }
+
+        let callbacks = HashMap::from([(
+            HookType::<EventTag, StateTag>::AfterEvent,
+            Closure::new(
+                |e: &Event<StateTag, Vec<u32>, Door>| -> Result<(), MyError> {
+                    let mut door = e.get_context();
+                    if e.is_event(EventTag::Open) {
+                        door.opened += 1;
+                    }
+                    door.log.push(e.event.to_string());
+                    Ok(())
+                },
+            ),
+        )]);
+        let mut fsm: FSM<_, Vec<u32>, _, _> =
+            FSM::new_with_context(StateTag::Closed, door(), callbacks, Door::default());
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+
+        // a clone updates its own copy
+        let mut clone = fsm.clone();
+        assert!(clone.on_event(EventTag::Close, None).is_ok());
+        assert_eq!(2, clone.get_context().log.len());
+
+        assert!(fsm.on_event(EventTag::Close, None).is_ok());
         assert_eq!(
-            ret.err().unwrap(),
-            FSMError::InternalError("my error: leave state fail".to_string())
+            Door {
+                opened: 1,
+                log: vec!["open".to_string(), "close".to_string()],
+            },
+            *fsm.get_context()
+        );
+        fsm.set_context(Door::default());
+        assert_eq!(0, fsm.get_context().opened);
+    }
+
+    #[test]
+    fn test_fsm_metadata() {
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::BeforeEvent,
+                Closure::new(|e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    let count = e.get_metadata::<u32>("count").map_or(0, |c| *c);
+                    e.set_metadata("count", count + 1);
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::Enter(StateTag::Closed),
+                Closure::new(|e| -> Result<(), MyError> {
+                    assert!(e.delete_metadata("owner"));
+                    Ok(())
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        fsm.set_metadata("owner", String::from("alice"));
+        assert!(fsm.on_event(EventTag::Open, None).is_ok());
+        assert_eq!(Some(1), fsm.get_metadata::<u32>("count").map(|c| *c));
+        assert_eq!(None, fsm.get_metadata::<u64>("count"));
+
+        // clones get their own copy
+        let mut clone = fsm.clone();
+        assert!(clone.on_event(EventTag::Close, None).is_ok());
+        assert_eq!(Some(2), clone.get_metadata::<u32>("count").map(|c| *c));
+        assert!(clone.get_metadata::<String>("owner").is_none());
+        assert_eq!(Some(1), fsm.get_metadata::<u32>("count").map(|c| *c));
+        assert_eq!(
+            "alice",
+            fsm.get_metadata::<String>("owner").unwrap().as_str()
+        );
+
+        assert!(fsm.delete_metadata("owner"));
+        assert!(!fsm.delete_metadata("owner"));
+    }
+
+    #[test]
+    fn test_fsm_hook_cache() {
+        let counter = AtomicU32::new(0);
+        let callbacks = HashMap::from([
+            (
+                HookType::<EventTag, StateTag>::Enter(StateTag::Opened),
+                Closure::new(|_e: &Event<StateTag, Vec<u32>>| -> Result<(), MyError> {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+            (
+                HookType::<EventTag, StateTag>::AfterEvent,
+                Closure::new(|_e| -> Result<(), MyError> {
+                    counter.fetch_add(10, Ordering::Relaxed);
+                    Ok(())
+                }),
+            ),
+        ]);
+        let mut fsm: FSMWithVec = FSM::new(StateTag::Closed, door(), callbacks);
+        assert_eq!(
+            HookCacheStats {
+                hits: 0,
+                misses: 0,
+                entries: 0
+            },
+            fsm.hook_cache_stats()
+        );
+
+        for _ in 0..3 {
+            assert!(fsm.on_event(EventTag::Open, None).is_ok());
+            assert!(fsm.on_event(EventTag::Close, None).is_ok());
+        }
+        assert_eq!(3 * 11 + 3 * 10, counter.load(Ordering::Relaxed));
+        assert_eq!(
+            HookCacheStats {
+                hits: 4,
+                misses: 2,
+                entries: 2
+            },
+            fsm.hook_cache_stats()
         );
-        assert_eq!(StateTag::Closed, fsm.get_current());
     }
 
     #[test]
@@ -814,11 +5358,57 @@ mod tests {
         assert_eq!(StateTag::Opened, fsm.get_current());
     }
 
+    #[test]
+    fn test_fsm_describe() {
+        let fsm: FSMWithVec = FSM::new(
+            StateTag::Closed,
+            door(),
+            vec![
+                (
+                    HookType::AfterEvent,
+                    Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+                ),
+                (
+                    HookType::Enter(StateTag::Opened),
+                    Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+                ),
+                (
+                    HookType::Before(EventTag::Open),
+                    Closure::new(|_e| -> Result<(), MyError> { Ok(()) }),
+                ),
+            ],
+        );
+        assert_eq!(
+            "current: closed\n\
+             transitions:\n  \
+               close: opened -> closed\n  \
+               open: closed -> opened\n\
+             hooks:\n  \
+               before_event(open)\n  \
+               enter_state(opened)\n  \
+               after_event",
+            fsm.describe()
+        );
+        assert_eq!(
+            "FSM { current: closed, \
+             transitions: [\"close: opened -> closed\", \"open: closed -> opened\"], \
+             hooks: {\"before_event(open)\": <Closure>, \"enter_state(opened)\": <Closure>, \"after_event\": <Closure>} }",
+            format!("{:?}", fsm)
+        );
+    }
+
     #[derive(Debug)]
     struct ActionHandler(AtomicU32);
     impl<S, I> Action<S, I> for &ActionHandler {
         type Err = MyError;
-        fn call(&self, _e: &Event<S, I>) -> Result<(), Self::Err> {
+        fn call(&self, e: &Event<S, I>) -> Result<(), Self::Err> {
+            let expected = match self.0.load(Ordering::Relaxed) {
+                0 => CallbackType::BeforeEvent,
+                1 => CallbackType::LeaveState,
+                2 => CallbackType::EnterState,
+                _ => CallbackType::AfterEvent,
+            };
+            assert_eq!(expected, e.phase());
             self.0.fetch_add(1, Ordering::Relaxed);
             Ok(())
         }
@@ -0,0 +1,253 @@
+use crate::{
+    action::Action,
+    error::FSMError,
+    event::{AsEvent, EventRecord, HistoryEntry},
+    fsm::FSMState,
+    FSM,
+};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, MutexGuard, TryLockError},
+};
+
+/// SharedFSM is an FSM shared between the parts of an application. Holding it
+/// gives full access, including reconfiguration.
+pub type SharedFSM<'a, S, I, F, C = ()> = Arc<Mutex<FSM<'a, S, I, F, C>>>;
+
+/// ReadHandle gives read-only access to a shared FSM, e.g. for a status page.
+///
+/// Like FireHandle, it never waits for the FSM: it fails with FSMError::Busy
+/// while the FSM is locked, so a callback reading through a handle on the FSM
+/// running it gets an error instead of a deadlock.
+pub struct ReadHandle<'a, S, I, F: Action<S, I, C>, C = ()> {
+    fsm: SharedFSM<'a, S, I, F, C>,
+}
+
+impl<'a, S, I, F, C> ReadHandle<'a, S, I, F, C>
+where
+    S: FSMState,
+    F: Action<S, I, C>,
+{
+    /// new returns a read-only handle to fsm.
+    pub fn new(fsm: SharedFSM<'a, S, I, F, C>) -> Self {
+        Self { fsm }
+    }
+
+    /// get_current returns the current state of the FSM.
+    pub fn get_current(&self) -> Result<S, FSMError<String>> {
+        Ok(lock(&self.fsm)?.get_current())
+    }
+
+    /// is returns true if state is the current state.
+    pub fn is<T: AsRef<S>>(&self, state: T) -> Result<bool, FSMError<String>> {
+        Ok(lock(&self.fsm)?.is(state))
+    }
+
+    /// can returns true if event can occur in the current state.
+    pub fn can<T: AsRef<str>>(&self, event: T) -> Result<bool, FSMError<String>> {
+        Ok(lock(&self.fsm)?.can(event))
+    }
+
+    /// is_finished returns true if the current state is a final state.
+    pub fn is_finished(&self) -> Result<bool, FSMError<String>> {
+        Ok(lock(&self.fsm)?.is_finished())
+    }
+
+    /// history returns a copy of the history of the FSM, see FSM::history.
+    pub fn history(&self) -> Result<Vec<HistoryEntry<S>>, FSMError<String>> {
+        Ok(lock(&self.fsm)?.history().cloned().collect())
+    }
+}
+
+impl<'a, S, I, F: Action<S, I, C>, C> Clone for ReadHandle<'a, S, I, F, C> {
+    fn clone(&self) -> Self {
+        Self {
+            fsm: self.fsm.clone(),
+        }
+    }
+}
+
+/// FireHandle lets its holder fire a fixed set of events on a shared FSM, and
+/// read its state, but not reconfigure it.
+pub struct FireHandle<'a, S, I, F: Action<S, I, C>, C = ()> {
+    fsm: SharedFSM<'a, S, I, F, C>,
+    events: Arc<HashSet<String>>,
+}
+
+impl<'a, S, I, F, C> FireHandle<'a, S, I, F, C>
+where
+    S: FSMState,
+    F: Action<S, I, C>,
+{
+    /// new returns a handle to fsm that can only fire the given events.
+    pub fn new<T: AsRef<str>>(
+        fsm: SharedFSM<'a, S, I, F, C>,
+        events: impl IntoIterator<Item = T>,
+    ) -> Self {
+        Self {
+            fsm,
+            events: Arc::new(
+                events
+                    .into_iter()
+                    .map(|event| event.as_ref().to_string())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// on_event is like FSM::on_event, but fails with FSMError::NotAllowed
+    /// if event is not one of the events of the handle, and with
+    /// FSMError::Busy if the FSM is locked, e.g. by a callback firing back
+    /// through the handle while the FSM runs it.
+    pub fn on_event<E: AsEvent>(
+        &self,
+        event: E,
+        args: Option<&I>,
+    ) -> Result<EventRecord<S>, FSMError<String>> {
+        let name = event.name()?;
+        if !self.events.contains(name) {
+            return Err(FSMError::NotAllowed(name.to_string()));
+        }
+        lock(&self.fsm)?.on_event(event, args)
+    }
+
+    /// read returns a read-only handle to the same FSM.
+    pub fn read(&self) -> ReadHandle<'a, S, I, F, C> {
+        ReadHandle::new(self.fsm.clone())
+    }
+}
+
+impl<'a, S, I, F: Action<S, I, C>, C> Clone for FireHandle<'a, S, I, F, C> {
+    fn clone(&self) -> Self {
+        Self {
+            fsm: self.fsm.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+/// Guard is the lock on a shared FSM held by a handle.
+type Guard<'f, 'a, S, I, F, C> = MutexGuard<'f, FSM<'a, S, I, F, C>>;
+
+// lock locks fsm, or fails with FSMError::Busy if it is already locked:
+// waiting would deadlock when the lock is held by the callback calling in.
+// Poisoning is ignored like crate::sync::lock does.
+fn lock<'f, 'a, S, I, F: Action<S, I, C>, C>(
+    fsm: &'f SharedFSM<'a, S, I, F, C>,
+) -> Result<Guard<'f, 'a, S, I, F, C>, FSMError<String>> {
+    match fsm.try_lock() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+        Err(TryLockError::WouldBlock) => Err(FSMError::Busy),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FireHandle, ReadHandle};
+    use crate::{Action, Event, FSMError, FSMState, HookType, FSM};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+    use strum::{AsRefStr, Display};
+
+    #[derive(Display, AsRefStr, Debug, Clone, Hash, PartialEq, Eq)]
+    #[strum(serialize_all = "snake_case")]
+    enum State {
+        Pending,
+        Approved,
+        Shipped,
+    }
+    impl FSMState for State {}
+    impl AsRef<Self> for State {
+        fn as_ref(&self) -> &Self {
+            self
+        }
+    }
+
+    // Noop is a thread-safe action, unlike Closure.
+    #[derive(Debug)]
+    struct Noop;
+    impl Action<State, Vec<u32>> for Noop {
+        type Err = FSMError<String>;
+        fn call(&self, _e: &Event<State, Vec<u32>>) -> Result<(), Self::Err> {
+            Ok(())
+        }
+    }
+
+    type Machine<'a> = FSM<'a, State, Vec<u32>, Noop>;
+
+    // Reenter reads the FSM running it through the handle in its slot, once
+    // it has one.
+    struct Reenter(Slot);
+    type Slot = Arc<Mutex<Option<ReadHandle<'static, State, Vec<u32>, Reenter>>>>;
+    impl std::fmt::Debug for Reenter {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("Reenter")
+        }
+    }
+    impl Action<State, Vec<u32>> for Reenter {
+        type Err = FSMError<String>;
+        fn call(&self, _e: &Event<State, Vec<u32>>) -> Result<(), Self::Err> {
+            let handle = self.0.lock().unwrap().clone();
+            handle.map_or(Ok(()), |handle| handle.get_current().map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn test_handles() {
+        let mut fsm: Machine = FSM::new(
+            State::Pending,
+            [
+                ("approve", State::Pending, State::Approved),
+                ("ship", State::Approved, State::Shipped),
+            ],
+            HashMap::new(),
+        );
+        assert!(fsm.set_history_capacity(4).is_ok());
+        let fsm = Arc::new(Mutex::new(fsm));
+        let reader = ReadHandle::new(fsm.clone());
+        let shipping = FireHandle::new(fsm.clone(), ["ship"]);
+
+        assert_eq!(
+            Err(FSMError::NotAllowed("approve".to_string())),
+            shipping.on_event("approve", None)
+        );
+        assert_eq!(Ok(true), reader.is(State::Pending));
+
+        assert!(fsm.lock().unwrap().on_event("approve", None).is_ok());
+        assert_eq!(Ok(true), reader.can("ship"));
+        assert!(shipping.on_event("ship", None).is_ok());
+        assert_eq!(Ok(State::Shipped), shipping.read().get_current());
+        assert_eq!(2, reader.history().unwrap().len());
+
+        // handles fail instead of waiting while the FSM is locked
+        let guard = fsm.lock().unwrap();
+        assert_eq!(Err(FSMError::Busy), reader.get_current());
+        assert_eq!(Err(FSMError::Busy), shipping.on_event("ship", None));
+        drop(guard);
+        assert_eq!(Ok(State::Shipped), reader.get_current());
+    }
+
+    #[test]
+    fn test_handles_reentrant() {
+        let slot = Arc::new(Mutex::new(None));
+        let fsm: FSM<_, Vec<u32>, _> = FSM::new(
+            State::Pending,
+            [("approve", State::Pending, State::Approved)],
+            [(HookType::Before("approve"), Reenter(slot.clone()))],
+        );
+        let fsm = Arc::new(Mutex::new(fsm));
+        *slot.lock().unwrap() = Some(ReadHandle::new(fsm.clone()));
+        let approving = FireHandle::new(fsm.clone(), ["approve"]);
+
+        // the callback reading through a handle gets an error, not a deadlock
+        assert_eq!(
+            Err(FSMError::InternalError("machine is busy".to_string())),
+            approving.on_event("approve", None)
+        );
+        assert_eq!(Ok(State::Pending), approving.read().get_current());
+        slot.lock().unwrap().take();
+    }
+}
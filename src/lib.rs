@@ -99,13 +99,32 @@
 //!
 
 mod action;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod dedup;
 mod error;
 mod event;
 mod fsm;
+mod handles;
+mod metadata;
+mod policy;
+mod profiler;
+mod queue;
+mod regions;
+mod resources;
+mod sync;
 
-pub use self::fsm::{CallbackType, EventDesc, FSMState, HookType, FSM};
+pub use self::fsm::{
+    CallbackType, EventDesc, FSMState, HookCacheStats, HookType, IntoEventDesc, PruneReport, FSM,
+};
 pub use action::{Action, Closure};
-pub use error::FSMError;
+pub use error::{ErrorKind, FSMError};
+pub use event::{
+    AsEvent, Bytes, EntryInfo, Event, EventRecord, HistoryEntry, Sequenced, Summarize,
+};
+pub use handles::{FireHandle, ReadHandle, SharedFSM};
+pub use policy::{FailurePolicy, Fallback, HookPolicy, NoTransitionPolicy, QueuePolicy};
+pub use regions::Regions;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,108 @@
+use crate::sync::lock;
+use std::{
+    any::Any,
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Value is a type-erased metadata value.
+type Value = Arc<dyn Any + Send + Sync>;
+
+/// Metadata is the per-instance data store shared by an FSM and the events
+/// passed to its callbacks.
+///
+/// The store is copy-on-write: a clone shares the data with the original
+/// until either of them writes to it.
+#[derive(Default)]
+pub(crate) struct Metadata {
+    // values maps keys to values of any type.
+    values: Mutex<Arc<HashMap<String, Value>>>,
+
+    // effects holds the (sequence, id) pairs of the side effects already run
+    // by Event::once.
+    effects: Mutex<Arc<BTreeSet<(u64, String)>>>,
+
+    // sandboxed is set on the copy owned by a sandbox, where Event::once
+    // runs no side effect.
+    sandboxed: bool,
+}
+
+impl Metadata {
+    /// sandboxed returns a copy of the store for a sandbox.
+    pub(crate) fn sandboxed(&self) -> Self {
+        Self {
+            sandboxed: true,
+            ..self.clone()
+        }
+    }
+
+    /// is_sandboxed returns true if the store belongs to a sandbox.
+    pub(crate) fn is_sandboxed(&self) -> bool {
+        self.sandboxed
+    }
+
+    /// set stores value under key, replacing any previous value.
+    pub(crate) fn set<T: Any + Send + Sync>(&self, key: String, value: T) {
+        Arc::make_mut(&mut self.values()).insert(key, Arc::new(value));
+    }
+
+    /// get returns the value stored under key, if any and if it is a T.
+    pub(crate) fn get<T: Any + Send + Sync>(&self, key: &str) -> Option<Arc<T>> {
+        self.values().get(key)?.clone().downcast().ok()
+    }
+
+    /// delete removes the value stored under key, and returns true if there
+    /// was one.
+    pub(crate) fn delete(&self, key: &str) -> bool {
+        let mut values = self.values();
+        values.contains_key(key) && Arc::make_mut(&mut values).remove(key).is_some()
+    }
+
+    /// record_effect records the side effect id of the event with sequence
+    /// seq, and returns false if it was already recorded.
+    pub(crate) fn record_effect(&self, seq: u64, id: String) -> bool {
+        let mut effects = self.effects_set();
+        !effects.contains(&(seq, id.clone())) && Arc::make_mut(&mut effects).insert((seq, id))
+    }
+
+    /// effects returns the recorded side effects, in order.
+    pub(crate) fn effects(&self) -> Vec<(u64, String)> {
+        self.effects_set().iter().cloned().collect()
+    }
+
+    /// prune_effects forgets the side effects of the events with a sequence
+    /// up to and including seq.
+    pub(crate) fn prune_effects(&self, seq: u64) {
+        let mut effects = self.effects_set();
+        let kept = match seq.checked_add(1) {
+            Some(next) => effects.range((next, String::new())..).cloned().collect(),
+            None => BTreeSet::new(),
+        };
+        *effects = Arc::new(kept);
+    }
+
+    /// set_effects replaces the recorded side effects with effects.
+    pub(crate) fn set_effects(&self, effects: impl IntoIterator<Item = (u64, String)>) {
+        *self.effects_set() = Arc::new(effects.into_iter().collect());
+    }
+
+    // values locks the store.
+    fn values(&self) -> MutexGuard<'_, Arc<HashMap<String, Value>>> {
+        lock(&self.values)
+    }
+
+    // effects_set locks the recorded side effects.
+    fn effects_set(&self) -> MutexGuard<'_, Arc<BTreeSet<(u64, String)>>> {
+        lock(&self.effects)
+    }
+}
+
+impl Clone for Metadata {
+    fn clone(&self) -> Self {
+        Self {
+            values: Mutex::new(self.values().clone()),
+            effects: Mutex::new(self.effects_set().clone()),
+            sandboxed: self.sandboxed,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::fsm::CallbackType;
+
+/// FailurePolicy decides what happens when a callback fails.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Stop the transition and return the error. The state is left as it is
+    /// at the time of the failure.
+    Abort,
+    /// Stop the transition, restore the source state and return the error.
+    /// The previous state and the last entry info are restored too, and the
+    /// sequence does not advance.
+    Rollback,
+    /// Ignore the error and carry on with the transition.
+    Ignore,
+    /// Poison the FSM and return the error.
+    Poison,
+}
+
+/// HookPolicy maps each callback type to the FailurePolicy applied when one of
+/// its callbacks fails.
+///
+/// The default aborts on `BeforeEvent`, `LeaveState` and `Transition`
+/// failures and ignores `EnterState` and `AfterEvent` failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookPolicy {
+    before_event: FailurePolicy,
+    leave_state: FailurePolicy,
+    transition: FailurePolicy,
+    enter_state: FailurePolicy,
+    after_event: FailurePolicy,
+}
+
+impl Default for HookPolicy {
+    fn default() -> Self {
+        Self {
+            before_event: FailurePolicy::Abort,
+            leave_state: FailurePolicy::Abort,
+            transition: FailurePolicy::Abort,
+            enter_state: FailurePolicy::Ignore,
+            after_event: FailurePolicy::Ignore,
+        }
+    }
+}
+
+impl HookPolicy {
+    /// with returns the policy with callback_type set to policy.
+    pub fn with(mut self, callback_type: CallbackType, policy: FailurePolicy) -> Self {
+        match callback_type {
+            CallbackType::BeforeEvent => self.before_event = policy,
+            CallbackType::LeaveState => self.leave_state = policy,
+            CallbackType::Transition => self.transition = policy,
+            CallbackType::EnterState => self.enter_state = policy,
+            CallbackType::AfterEvent => self.after_event = policy,
+            CallbackType::OnError | CallbackType::Any | CallbackType::None => {}
+        }
+        self
+    }
+
+    /// get returns the policy for callback_type.
+    pub fn get(&self, callback_type: &CallbackType) -> FailurePolicy {
+        match callback_type {
+            CallbackType::BeforeEvent => self.before_event,
+            CallbackType::LeaveState => self.leave_state,
+            CallbackType::Transition => self.transition,
+            CallbackType::EnterState => self.enter_state,
+            CallbackType::AfterEvent => self.after_event,
+            CallbackType::OnError | CallbackType::Any | CallbackType::None => FailurePolicy::Ignore,
+        }
+    }
+}
+
+/// QueuePolicy decides in which order posted events are dispatched.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Dispatch events with a higher priority first, and events with the same
+    /// priority in the order they were posted.
+    #[default]
+    Priority,
+    /// Dispatch events in the order they were posted, ignoring priorities.
+    Fifo,
+}
+
+/// NoTransitionPolicy decides what happens when an event leaves the FSM in the
+/// same state.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+pub enum NoTransitionPolicy {
+    /// Run the AfterEvent callbacks and fail with FSMError::NoTransition.
+    #[default]
+    Error,
+    /// Run the AfterEvent callbacks and succeed without leaving the state.
+    Ignore,
+    /// Leave and re-enter the state like an external self-transition, see
+    /// FSM::set_external.
+    Reenter,
+}
+
+/// Fallback is what to do with an event that has no transition from the
+/// current state, see FSM::set_fallback.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Fallback {
+    /// Fail with FSMError::UnknownEvent or FSMError::InvalidEvent.
+    Reject,
+    /// Swallow the event, leaving the FSM as it is.
+    Ignore,
+    /// Fire the named event instead.
+    Translate(String),
+}
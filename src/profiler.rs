@@ -0,0 +1,45 @@
+use crate::sync::lock;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// Profiler records the wall time spent in each hook across transitions.
+#[derive(Default)]
+pub(crate) struct Profiler {
+    // stacks maps folded stacks, e.g. "open;before_event(open)", to the
+    // total time spent in them.
+    stacks: Mutex<BTreeMap<String, Duration>>,
+}
+
+impl Profiler {
+    /// record adds elapsed to the time spent in stack.
+    pub(crate) fn record(&self, stack: String, elapsed: Duration) {
+        *self.stacks().entry(stack).or_default() += elapsed;
+    }
+
+    /// folded returns the recorded stacks in folded-stack format, one
+    /// "stack microseconds" line per stack, as consumed by flamegraph tools.
+    pub(crate) fn folded(&self) -> String {
+        let mut folded = String::new();
+        for (stack, elapsed) in self.stacks().iter() {
+            let _ = writeln!(folded, "{} {}", stack, elapsed.as_micros());
+        }
+        folded
+    }
+
+    // stacks locks the recorded stacks.
+    fn stacks(&self) -> MutexGuard<'_, BTreeMap<String, Duration>> {
+        lock(&self.stacks)
+    }
+}
+
+impl Clone for Profiler {
+    fn clone(&self) -> Self {
+        Self {
+            stacks: Mutex::new(self.stacks().clone()),
+        }
+    }
+}
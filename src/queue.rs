@@ -0,0 +1,124 @@
+use crate::{policy::QueuePolicy, sync::lock};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Queued is an event waiting to be dispatched.
+pub(crate) struct Queued<I> {
+    pub(crate) event: String,
+    pub(crate) args: Option<Arc<I>>,
+    pub(crate) priority: i32,
+}
+
+impl<I> Clone for Queued<I> {
+    fn clone(&self) -> Self {
+        Self {
+            event: self.event.clone(),
+            args: self.args.clone(),
+            priority: self.priority,
+        }
+    }
+}
+
+/// Queue holds the events posted to an FSM. It is shared with the events
+/// passed to callbacks, so they can post follow-up events.
+pub(crate) struct Queue<I> {
+    // events holds the posted events, oldest first.
+    events: Mutex<VecDeque<Queued<I>>>,
+}
+
+impl<I> Queue<I> {
+    /// push appends event to the queue.
+    pub(crate) fn push(&self, event: String, args: Option<I>, priority: i32) {
+        self.events().push_back(Queued {
+            event,
+            args: args.map(Arc::new),
+            priority,
+        });
+    }
+
+    /// push_front puts q back at the head of the queue, so it is the next
+    /// event popped among the events of its priority.
+    pub(crate) fn push_front(&self, q: Queued<I>) {
+        self.events().push_front(q);
+    }
+
+    /// pop removes and returns the next event according to policy, if any.
+    pub(crate) fn pop(&self, policy: QueuePolicy) -> Option<Queued<I>> {
+        let mut events = self.events();
+        match policy {
+            QueuePolicy::Fifo => events.pop_front(),
+            QueuePolicy::Priority => {
+                // the oldest of the events with the highest priority
+                let (i, _) = events
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .max_by_key(|(_, q)| q.priority)?;
+                events.remove(i)
+            }
+        }
+    }
+
+    /// len returns the number of events in the queue.
+    pub(crate) fn len(&self) -> usize {
+        self.events().len()
+    }
+
+    // events locks the queue.
+    fn events(&self) -> MutexGuard<'_, VecDeque<Queued<I>>> {
+        lock(&self.events)
+    }
+}
+
+impl<I> Default for Queue<I> {
+    fn default() -> Self {
+        Self {
+            events: Mutex::default(),
+        }
+    }
+}
+
+impl<I> Clone for Queue<I> {
+    fn clone(&self) -> Self {
+        Self {
+            events: Mutex::new(self.events().clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Queue;
+    use crate::policy::QueuePolicy;
+
+    // drain pops every event of queue according to policy.
+    fn drain(queue: &Queue<u32>, policy: QueuePolicy) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop(policy))
+            .map(|q| q.event)
+            .collect()
+    }
+
+    #[test]
+    fn test_queue_policy() {
+        let queue = Queue::default();
+        queue.push("close".to_string(), None, 0);
+        queue.push("open".to_string(), Some(1), 10);
+        queue.push("reset".to_string(), None, 0);
+        queue.push("abort".to_string(), None, 10);
+        assert_eq!(4, queue.len());
+
+        // the oldest of the events with the highest priority goes first
+        let ordered = queue.clone();
+        assert_eq!(
+            vec!["open", "abort", "close", "reset"],
+            drain(&ordered, QueuePolicy::Priority)
+        );
+        assert_eq!(
+            vec!["close", "open", "reset", "abort"],
+            drain(&queue, QueuePolicy::Fifo)
+        );
+        assert_eq!(0, queue.len());
+    }
+}
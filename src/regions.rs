@@ -0,0 +1,225 @@
+use crate::{
+    action::Action,
+    error::FSMError,
+    event::{AsEvent, EventRecord},
+    fsm::FSMState,
+    FSM,
+};
+
+/// Regions runs several FSMs side by side as the orthogonal regions of one
+/// machine, e.g. a connection state and an auth state, without building their
+/// cross product.
+///
+/// Each region keeps its own current state, transitions and callbacks. An
+/// event is dispatched to every region.
+pub struct Regions<'a, S, I, F: Action<S, I, C>, C = ()> {
+    regions: Vec<FSM<'a, S, I, F, C>>,
+}
+
+impl<'a, S, I, F, C> Regions<'a, S, I, F, C>
+where
+    S: FSMState,
+    F: Action<S, I, C>,
+{
+    /// new returns a machine made of regions, in dispatch order.
+    pub fn new<R: IntoIterator<Item = FSM<'a, S, I, F, C>>>(regions: R) -> Self {
+        Self {
+            regions: regions.into_iter().collect(),
+        }
+    }
+
+    /// get_current returns the current state of each region, in order.
+    pub fn get_current(&self) -> Vec<S> {
+        self.regions.iter().map(FSM::get_current).collect()
+    }
+
+    /// region returns the i-th region, if any.
+    pub fn region(&self, i: usize) -> Option<&FSM<'a, S, I, F, C>> {
+        self.regions.get(i)
+    }
+
+    /// region_mut returns the i-th region mutably, if any, e.g. to configure
+    /// it.
+    pub fn region_mut(&mut self, i: usize) -> Option<&mut FSM<'a, S, I, F, C>> {
+        self.regions.get_mut(i)
+    }
+
+    /// can returns true if event can occur in any region.
+    pub fn can<T: AsRef<str>>(&self, event: T) -> bool {
+        self.regions.iter().any(|region| region.can(event.as_ref()))
+    }
+
+    /// on_event dispatches event to every region, in order, and returns the
+    /// transitions that took place. Each region handles the event as on its
+    /// own, fallback, deferral and OnError callback included.
+    //
+    // A region rejecting the event, because it does not know it, cannot take
+    // it in its state, stays in its state or queues it, does not fail the
+    // event unless every region rejects it, in which case the most specific
+    // rejection is returned: FSMError::Queued, then FSMError::NoTransition,
+    // then FSMError::InvalidEvent, then FSMError::UnknownEvent. Any other
+    // error fails the event once every region ran; the regions that moved
+    // keep their new state.
+    pub fn on_event<E: AsEvent>(
+        &mut self,
+        event: E,
+        args: Option<&I>,
+    ) -> Result<Vec<EventRecord<S>>, FSMError<String>> {
+        let (sequence, event) = (event.sequence(), event.name()?);
+        let mut records = Vec::new();
+        let mut failure = None;
+        let mut rejection: Option<FSMError<String>> = None;
+        for region in self.regions.iter_mut() {
+            match region.fire(event, args, sequence) {
+                Ok(record) => records.push(record),
+                Err(err) => match rejection_rank(&err) {
+                    Some(rank) => {
+                        if rejection.as_ref().and_then(rejection_rank) < Some(rank) {
+                            rejection = Some(err);
+                        }
+                    }
+                    None => {
+                        failure.get_or_insert(err);
+                    }
+                },
+            }
+        }
+        match (failure, rejection) {
+            (Some(err), _) => Err(err),
+            (None, _) if !records.is_empty() => Ok(records),
+            (None, Some(err)) => Err(err),
+            (None, None) => Err(FSMError::UnknownEvent(event.to_string())),
+        }
+    }
+}
+
+// rejection_rank ranks the errors of a region rejecting an event from the
+// least to the most specific, or returns None for an actual failure.
+// The code is matched, so args summaries do not hide the error.
+fn rejection_rank(err: &FSMError<String>) -> Option<u8> {
+    match err.code() {
+        "unknown_event" => Some(0),
+        "invalid_event" => Some(1),
+        "no_transition" => Some(2),
+        "queued" => Some(3),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Regions;
+    use crate::{Closure, EventRecord, FSMError, FSMState, FSM};
+    use std::collections::HashMap;
+    use strum::{AsRefStr, Display};
+
+    #[derive(Display, AsRefStr, Debug, Clone, Hash, PartialEq, Eq)]
+    #[strum(serialize_all = "snake_case")]
+    enum State {
+        Disconnected,
+        Connected,
+        Anonymous,
+        LoggedIn,
+    }
+    impl FSMState for State {}
+    impl AsRef<Self> for State {
+        fn as_ref(&self) -> &Self {
+            self
+        }
+    }
+
+    type Machine<'a> = FSM<'a, State, Vec<u32>, Closure<'a, State, Vec<u32>, FSMError<String>>>;
+
+    #[test]
+    fn test_regions() {
+        let connection: Machine = FSM::new(
+            State::Disconnected,
+            [
+                ("connect", State::Disconnected, State::Connected),
+                ("reset", State::Connected, State::Disconnected),
+            ],
+            HashMap::new(),
+        );
+        let auth: Machine = FSM::new(
+            State::Anonymous,
+            [
+                ("login", State::Anonymous, State::LoggedIn),
+                ("reset", State::LoggedIn, State::Anonymous),
+                ("reset", State::Anonymous, State::Anonymous),
+            ],
+            HashMap::new(),
+        );
+        let mut regions = Regions::new([connection, auth]);
+        assert_eq!(
+            vec![State::Disconnected, State::Anonymous],
+            regions.get_current()
+        );
+
+        assert_eq!(
+            Ok(vec![EventRecord {
+                event: "connect".to_string(),
+                src: State::Disconnected,
+                dst: State::Connected,
+            }]),
+            regions.on_event("connect", None)
+        );
+        assert_eq!(
+            vec![State::Connected, State::Anonymous],
+            regions.get_current()
+        );
+
+        // the auth region stays anonymous while the connection resets
+        assert_eq!(Ok(1), regions.on_event("reset", None).map(|r| r.len()));
+        assert_eq!(
+            vec![State::Disconnected, State::Anonymous],
+            regions.get_current()
+        );
+        assert_eq!(Err(FSMError::NoTransition), regions.on_event("reset", None));
+
+        // the connection region rejects connect as it would alone
+        assert!(regions.on_event("connect", None).is_ok());
+        assert_eq!(
+            Err(FSMError::InvalidEvent(
+                "connect".to_string(),
+                "connected".to_string()
+            )),
+            regions.on_event("connect", None)
+        );
+
+        assert!(regions.can("login"));
+        assert!(!regions.can("logout"));
+        assert_eq!(
+            Err(FSMError::UnknownEvent("logout".to_string())),
+            regions.on_event("logout", None)
+        );
+        assert!(regions.region(1).unwrap().is(State::Anonymous));
+    }
+
+    #[test]
+    fn test_regions_per_region_handling() {
+        let connection: Machine = FSM::new(
+            State::Disconnected,
+            [("connect", State::Disconnected, State::Connected)],
+            HashMap::new(),
+        );
+        let auth: Machine = FSM::new(
+            State::Anonymous,
+            [("login", State::Anonymous, State::LoggedIn)],
+            HashMap::new(),
+        );
+        let mut regions = Regions::new([connection, auth]);
+        let auth = regions.region_mut(1).unwrap();
+        assert!(auth.set_deferrable(State::Anonymous, "connect").is_ok());
+
+        // the auth region queues connect while the connection region moves
+        assert_eq!(Ok(1), regions.on_event("connect", None).map(|r| r.len()));
+        assert_eq!(1, regions.region(1).unwrap().get_queued_count());
+
+        // queuing is the most specific rejection
+        assert_eq!(
+            Err(FSMError::Queued("connect".to_string())),
+            regions.on_event("connect", None)
+        );
+        assert_eq!(2, regions.region(1).unwrap().get_queued_count());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::sync::lock;
+use std::sync::{Mutex, MutexGuard};
+
+/// Resource is a value held for as long as the FSM stays in a state.
+type Resource = Box<dyn Send>;
+
+/// Resources holds the resources callbacks acquired for a state, which are
+/// dropped when the FSM leaves it.
+#[derive(Default)]
+pub(crate) struct Resources {
+    held: Mutex<Held>,
+}
+
+// Held is the content of Resources.
+#[derive(Default)]
+struct Held {
+    // resources holds the resources and the state they are held for, in the
+    // order they were acquired, each numbered by next at the time.
+    resources: Vec<(u64, String, Resource)>,
+    next: u64,
+}
+
+impl Resources {
+    /// hold keeps resource until state is left.
+    pub(crate) fn hold<T: Send + 'static>(&self, state: &str, resource: T) {
+        let mut held = self.held();
+        let n = held.next;
+        held.next += 1;
+        held.resources
+            .push((n, state.to_string(), Box::new(resource)));
+    }
+
+    /// release drops the resources of every state but the given ones, newest
+    /// first.
+    pub(crate) fn release(&self, states: &[&str]) {
+        drop(self.detach(states));
+    }
+
+    /// detach takes out the resources of every state but the given ones, to
+    /// be dropped with the returned Released, or given back with reattach.
+    pub(crate) fn detach(&self, states: &[&str]) -> Released {
+        let mut held = self.held();
+        let (kept, released) = std::mem::take(&mut held.resources)
+            .into_iter()
+            .partition(|(_, state, _)| states.contains(&state.as_str()));
+        held.resources = kept;
+        Released(released)
+    }
+
+    /// reattach gives back the resources taken out by detach, in the order
+    /// they were acquired among the ones held.
+    pub(crate) fn reattach(&self, mut released: Released) {
+        let mut held = self.held();
+        for resource in std::mem::take(&mut released.0) {
+            let i = held.resources.partition_point(|(n, _, _)| *n < resource.0);
+            held.resources.insert(i, resource);
+        }
+    }
+
+    /// len returns the number of resources held.
+    pub(crate) fn len(&self) -> usize {
+        self.held().resources.len()
+    }
+
+    /// is_empty returns true if no resources are held.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.held().resources.is_empty()
+    }
+
+    // held locks the store.
+    fn held(&self) -> MutexGuard<'_, Held> {
+        lock(&self.held)
+    }
+}
+
+/// Released holds the resources taken out by Resources::detach, and drops
+/// them newest first when it is dropped.
+pub(crate) struct Released(Vec<(u64, String, Resource)>);
+
+impl Drop for Released {
+    fn drop(&mut self) {
+        while let Some(resource) = self.0.pop() {
+            drop(resource);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Resources;
+    use std::sync::{Arc, Mutex};
+
+    struct Tracked(u32, Arc<Mutex<Vec<u32>>>);
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.1.lock().unwrap().push(self.0);
+        }
+    }
+
+    #[test]
+    fn test_resources_release_order() {
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let resources = Resources::default();
+        for (n, state) in [(1, "a"), (2, "b"), (3, "a"), (4, "c"), (5, "b")] {
+            resources.hold(state, Tracked(n, dropped.clone()));
+        }
+
+        // detached resources go back in the order they were acquired
+        let released = resources.detach(&["c"]);
+        resources.hold("c", Tracked(6, dropped.clone()));
+        resources.reattach(released);
+        assert_eq!(6, resources.len());
+        assert!(dropped.lock().unwrap().is_empty());
+
+        // resources of several states are dropped newest first
+        resources.release(&["c"]);
+        assert_eq!(vec![5, 3, 2, 1], *dropped.lock().unwrap());
+        resources.release(&[]);
+        assert_eq!(vec![5, 3, 2, 1, 6, 4], *dropped.lock().unwrap());
+        assert!(resources.is_empty());
+    }
+}
@@ -0,0 +1,9 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// lock locks mutex, ignoring poisoning.
+// The values the FSM keeps behind a mutex are only ever replaced or updated
+// in a single step, so a callback panicking while holding the lock leaves
+// them intact, as they were at the time.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}